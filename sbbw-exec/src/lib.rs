@@ -1,21 +1,54 @@
 use std::{
     collections::HashMap,
     error::Error,
+    fmt,
     fs::{read_dir, read_to_string, File},
-    path::PathBuf,
+    io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use colored::Colorize;
-use sbbw_widget_conf::{validate_config_toml, get_widgets_path};
+use sbbw_widget_conf::{get_widgets_path, validate_config_toml, PermissionsConfig, WidgetConfig};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use tracing::{debug, info, trace, warn};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Params {
-    pub method_id: i32,
+    /// Id of the call, drawn by the page from an `Uint32Array` and used by `rpc.cancel`
+    pub method_id: u32,
     pub method: String,
     pub command: String,
     pub args: Vec<String>,
+    /// Milliseconds the command can run, overrides `exec.timeout` of the widget
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+/// Time between checks of a running command for its exit, timeout or cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+///
+/// Flag shared with the thread running a command to stop it
+///
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
 }
 
 fn generate_hash_from_file(path: PathBuf) -> Result<String, Box<dyn Error>> {
@@ -32,60 +65,344 @@ fn generate_hash_from_file(path: PathBuf) -> Result<String, Box<dyn Error>> {
     Ok(format!("{:x}", hash.finalize()).to_string())
 }
 
-pub fn exec_command(pwd: String, params: Params) -> Result<String, String> {
-    let file = params.command;
-    println!("{}", file);
-    let mut args = params.args;
-    if file.starts_with("./") {
-        args.insert(0, file.clone());
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+///
+/// Fixed number of threads running the commands of a widget,
+/// the jobs beyond that number wait on a queue for a free thread
+///
+pub struct ExecPool {
+    sender: mpsc::Sender<Job>,
+}
+
+impl ExecPool {
+    /// Start `workers` threads, at least one
+    pub fn new(workers: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("exec-{}", index))
+                .spawn(move || loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        // the pool was dropped
+                        Err(_) => break,
+                    };
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        warn!(target: "exec", "A command job panicked");
+                    }
+                })
+                .expect("Cannot start the exec threads");
+        }
+        ExecPool { sender }
     }
-    println!("{:?}", args);
-    let output = if cfg!(target_os = "windows") {
-        std::process::Command::new("cmd")
-            .args(&["/C", "start"])
-            .args(&args)
-            .output()
-    } else {
-        if file.starts_with("./") {
-            println!("Execute sh command");
-            std::process::Command::new("sh")
-                .arg("-c")
-                .arg(&args.join(" "))
-                .current_dir(pwd)
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .output()
-        } else {
-            println!("Execute command");
-            std::process::Command::new(file)
-                .args(&args)
-                .current_dir(pwd)
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .output()
+
+    /// Queue `job` to run on the first free thread
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.sender.send(Box::new(job)).ok();
+    }
+}
+
+///
+/// Outcome of a command that was started
+///
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExecResult {
+    /// Exit code, `None` when the process was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// Milliseconds the command took to finish
+    pub duration: u64,
+}
+
+impl ExecResult {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+///
+/// Reason an RPC `exec` call was not run
+///
+#[derive(Debug)]
+pub enum ExecError {
+    /// Not an executable of the `scripts` folder nor listed on `permissions.commands`
+    Forbidden(String),
+    /// The command is allowed but cannot be started or waited
+    Spawn(String, io::Error),
+    /// The command ran longer than its timeout and was killed
+    TimedOut(String, Duration),
+    /// The command was killed by `rpc.cancel`
+    Cancelled(String),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::Forbidden(command) => {
+                write!(f, "Command \"{}\" is not allowed for this widget", command)
+            }
+            ExecError::Spawn(command, e) => write!(f, "Cannot run \"{}\": {}", command, e),
+            ExecError::TimedOut(command, timeout) => write!(
+                f,
+                "Command \"{}\" timed out after {}ms",
+                command,
+                timeout.as_millis()
+            ),
+            ExecError::Cancelled(command) => write!(f, "Command \"{}\" was cancelled", command),
         }
-    };
+    }
+}
+
+impl Error for ExecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExecError::Spawn(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
 
-    let stdout = String::from_utf8_lossy(&output.as_ref().unwrap().stdout);
-    let stderr = String::from_utf8_lossy(&output.as_ref().unwrap().stderr);
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
 
-    if !stderr.is_empty() {
-        println!("{}", stderr.red());
+///
+/// Program to run for a command called from the page of a widget.
+/// `./` commands must resolve, after following `..` and symlinks, to an executable
+/// inside `scripts`, any other command must be listed on `permissions.commands`
+///
+pub fn allowed_command(
+    scripts: &Path,
+    command: &str,
+    permissions: &PermissionsConfig,
+) -> Result<PathBuf, ExecError> {
+    let forbidden = || ExecError::Forbidden(command.to_string());
+    if permissions
+        .commands
+        .iter()
+        .any(|allowed| allowed == command)
+    {
+        return Ok(PathBuf::from(command));
+    }
+    if !command.starts_with("./") {
+        return Err(forbidden());
+    }
+    let scripts = scripts.canonicalize().map_err(|_| forbidden())?;
+    let path = scripts
+        .join(command)
+        .canonicalize()
+        .map_err(|_| forbidden())?;
+    if path.starts_with(&scripts) && is_executable(&path) {
+        Ok(path)
+    } else {
+        Err(forbidden())
     }
-    if !&stdout.is_empty() {
-        println!("{}", stdout.green());
+}
+
+///
+/// Run a command called from the page of a widget, when `allowed_command` accepts it,
+/// on the `scripts` folder of the widget. It is killed when `cancel` is set or
+/// its timeout is reached, the one of the call or else `exec.timeout`
+///
+pub fn exec_command(
+    pwd: String,
+    params: Params,
+    config: &WidgetConfig,
+    cancel: &CancelToken,
+) -> Result<ExecResult, ExecError> {
+    let program = allowed_command(Path::new(&pwd), &params.command, &config.permissions)?;
+    let timeout = params.timeout.unwrap_or(config.exec.timeout);
+    run(
+        &pwd,
+        &params.command,
+        program,
+        &params.args,
+        config.permissions.shell,
+        (timeout > 0).then(|| Duration::from_millis(timeout)),
+        cancel,
+    )
+}
+
+///
+/// Run `program` with `args` passed as they are, or through the shell when `shell` is set,
+/// `file` is the command as it was written by the widget
+///
+fn run(
+    pwd: &str,
+    file: &str,
+    program: PathBuf,
+    args: &[String],
+    shell: bool,
+    timeout: Option<Duration>,
+    cancel: &CancelToken,
+) -> Result<ExecResult, ExecError> {
+    debug!(
+        target: "exec",
+        command = %file,
+        ?args,
+        cwd = %pwd,
+        shell,
+        ?timeout,
+        "Running command"
+    );
+    let mut command = if shell {
+        let line = std::iter::once(file)
+            .chain(args.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut command = if cfg!(target_os = "windows") {
+            let mut command = Command::new("cmd");
+            command.arg("/C");
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c");
+            command
+        };
+        command.arg(line);
+        command
+    } else {
+        let mut command = Command::new(program);
+        command.args(args);
+        command
+    };
+    // cancelled while it was waiting for a free thread
+    if cancel.is_cancelled() {
+        return Err(ExecError::Cancelled(file.to_string()));
     }
+    // on its own process group, so the processes it starts are killed with it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let start = Instant::now();
+    let mut child = command
+        .current_dir(pwd)
+        // the stdin of the widget carries the commands of the daemon
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| ExecError::Spawn(file.to_string(), e))?;
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => {}
+            Err(e) => {
+                kill(&mut child);
+                return Err(ExecError::Spawn(file.to_string(), e));
+            }
+        }
+        if cancel.is_cancelled() {
+            kill(&mut child);
+            debug!(target: "exec", command = %file, "Command cancelled");
+            return Err(ExecError::Cancelled(file.to_string()));
+        }
+        if let Some(timeout) = timeout.filter(|timeout| start.elapsed() >= *timeout) {
+            kill(&mut child);
+            warn!(target: "exec", command = %file, ?timeout, "Command timed out");
+            return Err(ExecError::TimedOut(file.to_string(), timeout));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let result = ExecResult {
+        code: status.code(),
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+        duration: start.elapsed().as_millis() as u64,
+    };
 
-    println!(
-        "{}",
-        String::from_utf8_lossy(&output.as_ref().unwrap().stdout)
+    if !result.stderr.is_empty() {
+        debug!(target: "exec", stderr = %result.stderr.trim_end(), "Command wrote to stderr");
+    }
+    trace!(target: "exec", stdout = %result.stdout.trim_end(), "Command output");
+    debug!(
+        target: "exec",
+        command = %file,
+        code = ?result.code,
+        duration = result.duration,
+        "Command finished"
     );
 
-    Ok(stdout.to_string())
+    Ok(result)
+}
+
+///
+/// Read a pipe of the command until it is closed, on a thread so
+/// a full stderr cannot block the command while stdout is read
+///
+fn read_pipe<R: io::Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut content = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut content).ok();
+        }
+        String::from_utf8_lossy(&content).to_string()
+    })
+}
+
+/// Kill the command and the processes it started, then reap it
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    child.kill().ok();
+    child.wait().ok();
+}
+
+///
+/// Command of an autostart entry. They come from the config and not from the page,
+/// so they are not restricted by `permissions`: files of the `autostart` folder run
+/// through `sh -c` with the args joined, and on Windows everything runs with `cmd /C start`
+///
+fn autostart_command(pwd: &Path, cmd: &str, args: &[String]) -> Command {
+    let mut args = args.to_vec();
+    if cmd.starts_with("./") {
+        args.insert(0, cmd.to_string());
+    }
+    if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start"]).args(&args);
+        return command;
+    }
+    let mut command = if cmd.starts_with("./") {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(args.join(" "));
+        command
+    } else {
+        let mut command = Command::new(cmd);
+        command.args(&args);
+        command
+    };
+    command.current_dir(pwd);
+    command
 }
 
 pub fn autostarts() {
-    let config_dir = get_widgets_path();
+    let config_dir = match get_widgets_path() {
+        Ok(path) => path,
+        Err(e) => {
+            warn!(target: "autostart", "Cannot read the widgets directory: {}", e);
+            return;
+        }
+    };
 
     // Iterate over all widget files in the config directory
     for entry in read_dir(config_dir).unwrap() {
@@ -144,27 +461,47 @@ pub fn autostarts() {
 
         if changed {
             // Check if the file is a valid config file
-            println!("{}", "Autostarting widget".bright_yellow());
+            info!(
+                target: "autostart",
+                widget = %widget_path.display(),
+                "Running autostart commands"
+            );
             if widget_path.join("config.toml").exists() {
                 let config_toml =
                     validate_config_toml(widget_path.join("config.toml")).unwrap_or_default();
 
                 if config_toml.autostart.len() > 0 {
                     for autostart in config_toml.autostart {
-                        let params = Params {
-                            method_id: 0,
-                            method: "".to_string(),
-                            command: autostart.cmd.clone(),
-                            args: autostart.args,
-                        };
                         if !autostart.cmd.contains(".lua") {
-                            match exec_command(
-                                widget_path.join("autostart").to_str().unwrap().to_string(),
-                                params,
-                            ) {
+                            let pwd = widget_path.join("autostart");
+                            let mut command =
+                                autostart_command(&pwd, &autostart.cmd, &autostart.args);
+                            debug!(
+                                target: "autostart",
+                                command = %autostart.cmd,
+                                args = ?autostart.args,
+                                cwd = %pwd.display(),
+                                "Running command"
+                            );
+                            match command.output() {
+                                Ok(output) if !output.status.success() => {
+                                    let stderr = String::from_utf8_lossy(&output.stderr);
+                                    warn!(
+                                        target: "autostart",
+                                        command = %autostart.cmd,
+                                        code = ?output.status.code(),
+                                        stderr = %stderr.trim_end(),
+                                        "Autostart command failed"
+                                    );
+                                }
                                 Ok(_) => {}
                                 Err(e) => {
-                                    println!("{}", e);
+                                    warn!(
+                                        target: "autostart",
+                                        command = %autostart.cmd,
+                                        "Autostart command failed: {}",
+                                        e
+                                    );
                                 }
                             }
                         }
@@ -184,9 +521,300 @@ pub fn autostarts() {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[cfg(unix)]
+    fn write_script(path: &Path, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::write(
+            path,
+            "#!/bin/sh\nfor arg in \"$@\"; do echo \"[$arg]\"; done\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    /// Folder of a widget with an executable `scripts/args.sh` printing its args
+    #[cfg(unix)]
+    fn widget_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sbbw-exec-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(dir.join("scripts")).unwrap();
+        write_script(&dir.join("scripts").join("args.sh"), 0o755);
+        dir
+    }
+
+    fn params(command: &str, args: &[&str]) -> Params {
+        Params {
+            method_id: 1,
+            method: "exec".to_string(),
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout: None,
+        }
+    }
+
+    fn exec(
+        pwd: String,
+        params: Params,
+        permissions: &PermissionsConfig,
+    ) -> Result<ExecResult, ExecError> {
+        let config = WidgetConfig {
+            permissions: permissions.clone(),
+            ..Default::default()
+        };
+        exec_command(pwd, params, &config, &CancelToken::default())
+    }
+
+    #[test]
+    fn method_ids_take_the_whole_u32_range() {
+        let json = r#"{"method_id":4294967295,"method":"exec","command":"ls","args":[]}"#;
+        let params: Params = serde_json::from_str(json).unwrap();
+        assert_eq!(params.method_id, u32::MAX);
+        assert_eq!(params.timeout, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scripts_receive_the_args_verbatim() {
+        let dir = widget_dir("verbatim");
+        let pwd = dir.join("scripts").to_str().unwrap().to_string();
+        let permissions = PermissionsConfig::default();
+        let output = exec(
+            pwd,
+            params(
+                "./args.sh",
+                &["two words", "$(touch injected)", "; touch injected"],
+            ),
+            &permissions,
+        )
+        .unwrap();
+
+        assert_eq!(
+            output.stdout,
+            "[two words]\n[$(touch injected)]\n[; touch injected]\n"
+        );
+        assert!(output.success());
+        assert!(!dir.join("scripts").join("injected").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_mode_is_opt_in() {
+        let dir = widget_dir("shell");
+        let pwd = dir.join("scripts").to_str().unwrap().to_string();
+        let permissions = PermissionsConfig {
+            shell: true,
+            ..Default::default()
+        };
+        let output = exec(
+            pwd,
+            params("./args.sh", &["two words", "$(echo expanded)"]),
+            &permissions,
+        )
+        .unwrap();
+
+        assert_eq!(output.stdout, "[two]\n[words]\n[expanded]\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_scripts_and_listed_commands_are_allowed() {
+        let dir = widget_dir("allowed");
+        let scripts = dir.join("scripts");
+        write_script(&dir.join("outside.sh"), 0o755);
+        write_script(&scripts.join("data.sh"), 0o644);
+        std::os::unix::fs::symlink(dir.join("outside.sh"), scripts.join("link.sh")).unwrap();
+        let mut permissions = PermissionsConfig::default();
+
+        assert_eq!(
+            allowed_command(&scripts, "./args.sh", &permissions).unwrap(),
+            scripts.canonicalize().unwrap().join("args.sh")
+        );
+        for command in [
+            "./../outside.sh",
+            "./link.sh",
+            "./data.sh",
+            "./missing.sh",
+            "./",
+            "echo",
+            "/bin/echo",
+            "../outside.sh",
+        ] {
+            match allowed_command(&scripts, command, &permissions) {
+                Err(ExecError::Forbidden(forbidden)) => assert_eq!(forbidden, command),
+                other => panic!("{} should be forbidden, got {:?}", command, other),
+            }
+        }
+
+        permissions.commands = vec!["echo".to_string()];
+        let output = exec(
+            scripts.to_str().unwrap().to_string(),
+            params("echo", &["listed"]),
+            &permissions,
+        )
+        .unwrap();
+        assert_eq!(output.stdout, "listed\n");
+        assert!(allowed_command(&scripts, "/bin/echo", &permissions).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failures_keep_the_code_and_stderr() {
+        let pwd = std::env::temp_dir().to_str().unwrap().to_string();
+        let permissions = PermissionsConfig {
+            commands: vec!["sh".to_string()],
+            ..Default::default()
+        };
+        let result = exec(
+            pwd,
+            params("sh", &["-c", "echo partial; echo broken >&2; exit 3"]),
+            &permissions,
+        )
+        .unwrap();
+
+        assert_eq!(result.code, Some(3));
+        assert!(!result.success());
+        assert_eq!(result.stdout, "partial\n");
+        assert_eq!(result.stderr, "broken\n");
+    }
+
+    #[test]
+    fn unknown_commands_are_not_run() {
+        let pwd = std::env::temp_dir().to_str().unwrap().to_string();
+        let permissions = PermissionsConfig {
+            commands: vec!["sbbw-missing-command".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            exec(
+                pwd.clone(),
+                params("./sbbw-missing-script", &[]),
+                &permissions
+            ),
+            Err(ExecError::Forbidden(_))
+        ));
+        assert!(matches!(
+            exec(pwd, params("sbbw-missing-command", &[]), &permissions),
+            Err(ExecError::Spawn(_, _))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slow_commands_are_killed_with_their_children() {
+        let dir = widget_dir("timeout");
+        let survivor = dir.join("survived");
+        let config = WidgetConfig {
+            permissions: PermissionsConfig {
+                commands: vec!["sh".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let script = format!("(sleep 0.5; touch {}) & sleep 10", survivor.display());
+        let mut call = params("sh", &["-c", &script]);
+        call.timeout = Some(100);
+        let start = Instant::now();
+
+        match exec_command(
+            dir.to_str().unwrap().to_string(),
+            call,
+            &config,
+            &CancelToken::default(),
+        ) {
+            Err(ExecError::TimedOut(_, timeout)) => {
+                assert_eq!(timeout, Duration::from_millis(100))
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_secs(1));
+        assert!(!survivor.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn running_commands_can_be_cancelled() {
+        let config = WidgetConfig {
+            permissions: PermissionsConfig {
+                commands: vec!["sleep".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let cancel = CancelToken::default();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+        let start = Instant::now();
+
+        assert!(matches!(
+            exec_command(
+                std::env::temp_dir().to_str().unwrap().to_string(),
+                params("sleep", &["10"]),
+                &config,
+                &cancel,
+            ),
+            Err(ExecError::Cancelled(_))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // cancelled before a thread was free, it never starts
+        assert!(matches!(
+            exec_command(
+                std::env::temp_dir().to_str().unwrap().to_string(),
+                params("sbbw-missing-command", &[]),
+                &WidgetConfig {
+                    permissions: PermissionsConfig {
+                        commands: vec!["sbbw-missing-command".to_string()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                &cancel,
+            ),
+            Err(ExecError::Cancelled(_))
+        ));
+    }
+
+    #[test]
+    fn the_pool_limits_the_running_jobs() {
+        let pool = ExecPool::new(2);
+        let active = Arc::new(Mutex::new((0, 0)));
+        let (sender, receiver) = mpsc::channel();
+        // a panicking job does not take its thread down
+        pool.execute(|| panic!("a broken job"));
+        for _ in 0..6 {
+            let active = active.clone();
+            let sender = sender.clone();
+            pool.execute(move || {
+                {
+                    let mut active = active.lock().unwrap();
+                    active.0 += 1;
+                    active.1 = active.1.max(active.0);
+                }
+                thread::sleep(Duration::from_millis(50));
+                active.lock().unwrap().0 -= 1;
+                sender.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..6 {
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(active.lock().unwrap().1, 2);
+    }
 }
@@ -0,0 +1,220 @@
+use std::{
+    fmt, io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+///
+/// Location of an error on a config file
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    /// Byte range on the file
+    pub range: Range<usize>,
+    /// Line of the start of the range, starting from 1
+    pub line: usize,
+    /// Column of the start of the range, starting from 1
+    pub column: usize,
+    /// Dotted path of the field at the range, like `restart.max_retries`
+    pub field: Option<String>,
+    /// Text of the line where the range starts
+    pub source_line: String,
+}
+
+impl Span {
+    pub(crate) fn new(source: &str, range: Range<usize>) -> Self {
+        let start = range.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let source_line = source[line_start..line_end].trim_end_matches('\r');
+        Span {
+            line: source[..line_start].matches('\n').count() + 1,
+            column: source[line_start..start].chars().count() + 1,
+            field: field_at(&source[..line_start], &source[line_start..start]),
+            source_line: source_line.to_string(),
+            range,
+        }
+    }
+}
+
+///
+/// Name of the field set on the line, prefixed by the table it belongs to.
+/// `before` is the text before that line, `prefix` the line up to the error
+///
+fn field_at(before: &str, prefix: &str) -> Option<String> {
+    let (key, _) = prefix.split_once('=')?;
+    let key = key.trim().trim_matches('"');
+    let table = before
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| line.starts_with('['))
+        .map(|line| line.trim_matches(|c| c == '[' || c == ']').trim());
+    match table {
+        Some(table) => Some(format!("{}.{}", table, key)),
+        None => Some(key.to_string()),
+    }
+}
+
+///
+/// Error found on the content of a config file
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// File with the error, `None` when the config was not read from a file
+    pub path: Option<PathBuf>,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl ParseError {
+    pub(crate) fn new(source: &str, error: toml::de::Error) -> Box<Self> {
+        Box::new(ParseError {
+            path: None,
+            message: error.message().to_string(),
+            span: error.span().map(|range| Span::new(source, range)),
+        })
+    }
+
+    ///
+    /// Multiline report of the error pointing at the offending line, like
+    ///
+    /// ```text
+    /// error: Invalid size or position `abc`: expected a number at `abc`
+    ///  --> ~/.config/sbbw/widgets/bar/config.toml:2:9
+    ///   |
+    /// 2 | width = "abc"
+    ///   |         ^^^^^ on field `width`
+    /// ```
+    ///
+    pub fn diagnostic(&self) -> String {
+        let mut report = format!("error: {}\n", self.message);
+        let path = self
+            .path
+            .as_deref()
+            .unwrap_or_else(|| Path::new("config.toml"));
+        let span = match &self.span {
+            Some(span) => span,
+            None => {
+                report.push_str(&format!(" --> {}\n", path.display()));
+                return report;
+            }
+        };
+        let gutter = " ".repeat(span.line.to_string().len());
+        let width = span
+            .range
+            .len()
+            .min(span.source_line.len().saturating_sub(span.column - 1))
+            .max(1);
+        let label = span
+            .field
+            .as_ref()
+            .map(|field| format!(" on field `{}`", field))
+            .unwrap_or_default();
+        report.push_str(&format!(
+            "{gutter}--> {}:{}:{}\n{gutter} |\n{} | {}\n{gutter} | {}{}{}\n",
+            path.display(),
+            span.line,
+            span.column,
+            span.line,
+            span.source_line,
+            " ".repeat(span.column - 1),
+            "^".repeat(width),
+            label,
+            gutter = gutter,
+        ));
+        report
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        match (&self.path, &self.span) {
+            (Some(path), Some(span)) => {
+                write!(f, " at {}:{}:{}", path.display(), span.line, span.column)
+            }
+            (Some(path), None) => write!(f, " at {}", path.display()),
+            (None, Some(span)) => write!(f, " at line {}, column {}", span.line, span.column),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+///
+/// Reasons a widget config cannot be loaded
+///
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file does not exist
+    NotFound(PathBuf),
+    /// The config file exists but cannot be read
+    Io(PathBuf, io::Error),
+    /// The config file is not valid TOML
+    Syntax(Box<ParseError>),
+    /// `width`, `height`, `x`, `y` or a side of `margin` is not a valid length
+    InvalidSize(Box<ParseError>),
+    /// A field has a value of the wrong type or out of range
+    InvalidField(Box<ParseError>),
+}
+
+impl ConfigError {
+    pub(crate) fn with_path(mut self, path: &Path) -> Self {
+        if let Some(error) = self.parse_error_mut() {
+            error.path = Some(path.to_path_buf());
+        }
+        self
+    }
+
+    /// Details of the error when it is on the content of the file
+    pub fn parse_error(&self) -> Option<&ParseError> {
+        match self {
+            ConfigError::Syntax(error)
+            | ConfigError::InvalidSize(error)
+            | ConfigError::InvalidField(error) => Some(error.as_ref()),
+            ConfigError::NotFound(_) | ConfigError::Io(_, _) => None,
+        }
+    }
+
+    fn parse_error_mut(&mut self) -> Option<&mut ParseError> {
+        match self {
+            ConfigError::Syntax(error)
+            | ConfigError::InvalidSize(error)
+            | ConfigError::InvalidField(error) => Some(error.as_mut()),
+            ConfigError::NotFound(_) | ConfigError::Io(_, _) => None,
+        }
+    }
+
+    /// Compiler-style report of the error, see `ParseError::diagnostic`
+    pub fn diagnostic(&self) -> String {
+        match self.parse_error() {
+            Some(error) => error.diagnostic(),
+            None => format!("error: {}\n", self),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotFound(path) => {
+                write!(f, "Config file not found: {}", path.display())
+            }
+            ConfigError::Io(path, e) => {
+                write!(f, "Cannot read config file {}: {}", path.display(), e)
+            }
+            ConfigError::Syntax(error)
+            | ConfigError::InvalidSize(error)
+            | ConfigError::InvalidField(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -1,36 +1,63 @@
-#[allow(unused_imports)]
-use colored::*;
 use serde::{
     de::{self, Deserializer},
     Deserialize, Serialize, Serializer,
 };
-use std::{path::PathBuf, fs};
+use std::{
+    fs,
+    io,
+    path::PathBuf,
+    time::Duration,
+};
+
+mod error;
+mod placement;
+mod size;
+pub use error::{ConfigError, ParseError, Span};
+use placement::deserialize_margin;
+pub use placement::{Anchor, Edge, Margin, MonitorSelector};
+use size::{catch_length_errors, RawLength};
+pub use size::{Length, Rect, Unit, REM_PX};
 
 fn deserialize_widget_size<'de, D>(de: D) -> Result<WidgetSize, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(de)?;
-    match s.to_ascii_lowercase().as_str() {
-        "max" => Ok(WidgetSize::Max),
-        _ => {
-            let v = s.parse::<f64>();
-            if v.is_err() {
-                return Err(de::Error::custom(format!(
-                    "[{}] Invalid widget size (Cannot convert into f64): {}",
-                    "Error".red().bold(),
-                    s
-                )));
-            }
-            Ok(WidgetSize::Value(v.unwrap()))
-        } // _ => Err(serde::de::Error::custom("error trying to deserialize rotation policy config"))
+    match RawLength::deserialize(de)? {
+        RawLength::Number(value) => Ok(WidgetSize::Value(Length::px(value))),
+        RawLength::Text(s) if s.eq_ignore_ascii_case("max") => Ok(WidgetSize::Max),
+        RawLength::Text(s) => RawLength::parse(&s).map(WidgetSize::Value),
+    }
+}
+
+fn deserialize_opacity<'de, D>(de: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opacity = f64::deserialize(de)?;
+    if (0.0..=1.0).contains(&opacity) {
+        Ok(opacity)
+    } else {
+        Err(de::Error::custom(format!(
+            "opacity must be between 0 and 1, found {}",
+            opacity
+        )))
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum WidgetSize {
     Max,
-    Value(f64),
+    Value(Length),
+}
+
+impl WidgetSize {
+    /// Logical pixels of the size, where `available` is the monitor dimension it refers to
+    pub fn resolve(&self, available: f64) -> f64 {
+        match self {
+            WidgetSize::Max => available,
+            WidgetSize::Value(length) => length.resolve(available),
+        }
+    }
 }
 
 impl Serialize for WidgetSize {
@@ -40,7 +67,7 @@ impl Serialize for WidgetSize {
     {
         match self {
             WidgetSize::Max => serializer.serialize_str("Max"),
-            WidgetSize::Value(v) => serializer.serialize_str(&v.to_string()),
+            WidgetSize::Value(v) => v.serialize(serializer),
         }
     }
 }
@@ -59,23 +86,209 @@ pub struct AutoStartCommand {
     pub args: Vec<String>,
 }
 
+///
+/// What the page of the widget is allowed to run with `rpc.call`, by default only
+/// the executables of its `scripts` folder
+///
+#[derive(Clone, Serialize, Default, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(default)]
+pub struct PermissionsConfig {
+    ///
+    /// Run the commands through `sh -c` (`cmd /C` on Windows) with the args joined by spaces.
+    /// Any page able to call the widget can then run arbitrary shell code
+    ///
+    pub shell: bool,
+    ///
+    /// Programs other than the executables of `scripts` the page can run,
+    /// like `playerctl` or `/usr/bin/brightnessctl`, matched as they are written
+    ///
+    pub commands: Vec<String>,
+}
+
+///
+/// How the commands called from the page of the widget are run
+///
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(default)]
+pub struct ExecConfig {
+    ///
+    /// Milliseconds a command can run before it is killed, along with the processes it started,
+    /// when the call does not set its own. `0` waits forever
+    ///
+    pub timeout: u64,
+    /// Commands run at the same time, the other calls wait for one of them to finish
+    pub concurrency: usize,
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        ExecConfig {
+            timeout: 30000,
+            concurrency: 4,
+        }
+    }
+}
+
+///
+/// Message sent by the daemon to a running widget through its stdin,
+/// serialized as one JSON object per line
+///
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum WidgetCommand {
+    /// Dispatch `beforeclose` on the page and exit
+    Shutdown,
+    /// Load the page again, sent when the files of `ui` change
+    Reload,
+}
+
+///
+/// Kind of window told to the window manager, which decides
+/// how the widget is stacked, focused and decorated
+///
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowType {
+    #[default]
+    Normal,
+    /// Panels and bars, kept above the windows and on all workspaces
+    Dock,
+    /// Drawn below all the windows, like desktop icons
+    Desktop,
+    Notification,
+    Splash,
+}
+
+///
+/// Stacking layer of the widget on Wayland compositors with layer shell
+///
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    /// Below the desktop icons, like wallpapers
+    Background,
+    /// Below the windows
+    Bottom,
+    /// Above the windows, where bars and panels go
+    #[default]
+    Top,
+    /// Above everything, even fullscreen windows
+    Overlay,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(default)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    pub max_retries: u32,
+    /// Milliseconds to wait before the first restart, doubled on each consecutive retry
+    pub backoff: u64,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        RestartConfig {
+            policy: RestartPolicy::Never,
+            max_retries: 5,
+            backoff: 1000,
+        }
+    }
+}
+
+/// Upper bound for the restart backoff
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+impl RestartConfig {
+    ///
+    /// Check if the widget must be launched again after it exited,
+    /// `retries` is the number of consecutive restarts already done
+    ///
+    pub fn should_restart(&self, success: bool, retries: u32) -> bool {
+        if retries >= self.max_retries {
+            return false;
+        }
+        match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !success,
+            RestartPolicy::Always => true,
+        }
+    }
+
+    pub fn backoff_for(&self, retries: u32) -> Duration {
+        let factor = 2u64.saturating_pow(retries);
+        Duration::from_millis(self.backoff.saturating_mul(factor)).min(MAX_BACKOFF)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "lowercase")]
 #[serde(default)]
 pub struct WidgetConfig {
     pub name: String,
-    pub class_name: String, // TODO: add support
+    ///
+    /// WM_CLASS (instance and class) on X11 and app_id on Wayland, for window manager rules.
+    /// Derived from `name` when not set
+    ///
+    pub class_name: String,
     #[serde(deserialize_with = "deserialize_widget_size")]
     pub width: WidgetSize,
     #[serde(deserialize_with = "deserialize_widget_size")]
     pub height: WidgetSize,
-    pub x: f32,
-    pub y: f32,
-    pub transparent: bool, // Only works on Windows and Mac. For the linux users can be set with compositor
-    pub blur: bool,
+    pub monitor: MonitorSelector,
+    /// Corner, edge or center of the work area the widget is aligned to
+    pub anchor: Anchor,
+    #[serde(deserialize_with = "deserialize_margin")]
+    pub margin: Margin,
+    /// Offsets from the anchored position, to the right and down
+    pub x: Length,
+    pub y: Length,
+    /// Transparent background of the page, the window is see-through where the page is
+    pub transparent: bool,
+    pub blur: bool, // Only works on Windows and Mac. For the linux users can be set with compositor
     pub always_on_top: bool,
     pub stick: bool,
-    pub autostart: Vec<AutoStartCommand>
+    /// Let the mouse events go through the widget to the windows below
+    pub click_through: bool,
+    /// Hide the widget from taskbars and alt-tab lists
+    pub skip_taskbar: bool,
+    /// Hide the widget from workspace pagers
+    pub skip_pager: bool,
+    /// Let the widget take the keyboard focus when clicked
+    pub accept_focus: bool,
+    /// Focus the widget when it is opened
+    pub focus_on_map: bool,
+    /// Opacity of the whole window, from 0 to 1
+    #[serde(deserialize_with = "deserialize_opacity")]
+    pub opacity: f64,
+    pub window_type: WindowType,
+    /// Keep other windows out of the space of the widget on the edge it is anchored to
+    pub reserve_space: bool,
+    pub layer: Layer,
+    ///
+    /// Pixels kept free for the widget on the edge it is anchored to on Wayland,
+    /// by default its size when `reserve_space` is set. `-1` also covers the bars of others
+    ///
+    pub exclusive_zone: Option<i32>,
+    /// Let the widget take the keyboard focus on Wayland
+    pub keyboard_interactivity: bool,
+    pub autostart: Vec<AutoStartCommand>,
+    pub permissions: PermissionsConfig,
+    pub exec: ExecConfig,
+    pub restart: RestartConfig,
+    /// Milliseconds to wait for the widget to exit on close before killing it
+    pub shutdown_timeout: u64,
 }
 
 impl Default for WidgetConfig {
@@ -83,15 +296,33 @@ impl Default for WidgetConfig {
         WidgetConfig {
             name: "Internal".to_string(),
             class_name: "Internal_Class".to_string(),
-            width: WidgetSize::Value(200.0),
+            width: WidgetSize::Value(Length::px(200.0)),
             height: WidgetSize::Max,
-            x: 0.0,
-            y: 0.0,
+            monitor: MonitorSelector::default(),
+            anchor: Anchor::default(),
+            margin: Margin::default(),
+            x: Length::default(),
+            y: Length::default(),
             transparent: true,
             blur: true,
             always_on_top: true,
             stick: true,
+            click_through: false,
+            skip_taskbar: true,
+            skip_pager: true,
+            accept_focus: true,
+            focus_on_map: false,
+            opacity: 1.0,
+            window_type: WindowType::default(),
+            reserve_space: false,
+            layer: Layer::default(),
+            exclusive_zone: None,
+            keyboard_interactivity: false,
             autostart: vec![],
+            permissions: PermissionsConfig::default(),
+            exec: ExecConfig::default(),
+            restart: RestartConfig::default(),
+            shutdown_timeout: 3000,
         }
     }
 }
@@ -100,7 +331,7 @@ impl WidgetConfig {
     pub fn new(name: String) -> Self {
         WidgetConfig {
             name: name.clone(),
-            class_name: name.to_uppercase().replace(" ", "_"),
+            class_name: default_class_name(&name),
             ..Default::default()
         }
     }
@@ -119,7 +350,7 @@ impl WidgetConfig {
         self.height = height;
     }
 
-    pub fn set_position(&mut self, x: f32, y: f32) {
+    pub fn set_position(&mut self, x: Length, y: Length) {
         self.x = x;
         self.y = y;
     }
@@ -135,68 +366,190 @@ impl WidgetConfig {
     pub fn set_always_on_top(&mut self, allways_on_top: bool) {
         self.always_on_top = allways_on_top;
     }
+
+    ///
+    /// Place the widget on the work area of a monitor. The margins are taken
+    /// from the work area, then the widget is aligned to its anchor inside what is
+    /// left and moved by the offsets. Horizontal values are relative to the width
+    /// of that inner area and vertical ones to its height
+    ///
+    pub fn geometry(&self, work_area: Rect) -> Rect {
+        let area = self.margin.shrink(work_area);
+        let width = self.width.resolve(area.width);
+        let height = self.height.resolve(area.height);
+        let (x, y) = self.anchor.place(area, width, height);
+        Rect {
+            x: x + self.x.resolve(area.width),
+            y: y + self.y.resolve(area.height),
+            width,
+            height,
+        }
+    }
+
+    ///
+    /// Edge of the screen where `reserve_space` keeps the space of the widget,
+    /// `None` when it is disabled or the widget is centered
+    ///
+    pub fn reserved_edge(&self, geometry: Rect) -> Option<Edge> {
+        if self.reserve_space {
+            self.anchor.edge(geometry)
+        } else {
+            None
+        }
+    }
+}
+
+///
+/// Check if a class name only has ASCII letters, digits, `_`, `-` and `.`,
+/// which window manager rules can match without quoting or escaping
+///
+pub fn is_valid_class_name(class_name: &str) -> bool {
+    !class_name.is_empty() && class_name.chars().all(is_class_name_char)
 }
 
-fn validate_config_from_string(config: &str) -> Result<WidgetConfig, String> {
-    match toml::from_str::<'_, WidgetConfig>(&config) {
-        Ok(conf) => Ok(conf),
-        Err(e) => Err(format!(
-            "[{}] Config file is not valid: {}",
-            "Error".red().bold(),
-            e
-        )),
+fn is_class_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Class name of a widget without `class_name`, like `MY_BAR` for `my bar`
+pub fn default_class_name(name: &str) -> String {
+    let class_name: String = name
+        .to_uppercase()
+        .chars()
+        .map(|c| if is_class_name_char(c) { c } else { '_' })
+        .collect();
+    if class_name.is_empty() {
+        "SBBW".to_string()
+    } else {
+        class_name
     }
 }
-pub fn validate_config_toml(conf_path: PathBuf) -> Result<WidgetConfig, String> {
-    if !conf_path.exists() {
-        return Err(format!(
-            "[{}] Config file for window not found: {}",
-            "Error".red().bold(),
-            conf_path.display()
-        ));
+
+fn validate_config_from_string(config: &str) -> Result<WidgetConfig, ConfigError> {
+    // parse the raw document first, to tell bad TOML from bad values
+    let table = match config.parse::<toml::Table>() {
+        Ok(table) => table,
+        Err(e) => return Err(ConfigError::Syntax(ParseError::new(config, e))),
+    };
+    let (conf, invalid_size) = catch_length_errors(|| toml::from_str::<WidgetConfig>(config));
+    let mut conf = conf.map_err(|e| {
+        let error = ParseError::new(config, e);
+        if invalid_size {
+            ConfigError::InvalidSize(error)
+        } else {
+            ConfigError::InvalidField(error)
+        }
+    })?;
+    if !table.contains_key("class_name") {
+        conf.class_name = default_class_name(&conf.name);
     }
-    let conf_str = std::fs::read_to_string(conf_path).unwrap();
-    validate_config_from_string(&conf_str.as_str())
+    Ok(conf)
+}
+pub fn validate_config_toml(conf_path: PathBuf) -> Result<WidgetConfig, ConfigError> {
+    let conf_str = match fs::read_to_string(&conf_path) {
+        Ok(conf_str) => conf_str,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(ConfigError::NotFound(conf_path))
+        }
+        Err(e) => return Err(ConfigError::Io(conf_path, e)),
+    };
+    validate_config_from_string(&conf_str).map_err(|e| e.with_path(&conf_path))
+}
+
+/// Env var with the log filter, like `info` or `daemon=debug,exec=trace`,
+/// exported by the daemon so its widgets log at the same level
+pub const LOG_ENV: &str = "SBBW_LOG";
+
+/// Env var relocating the root of the config, `~/.config/sbbw` by default
+pub const CONFIG_DIR_ENV: &str = "SBBW_CONFIG_DIR";
+
+/// Root of the config set with `SBBW_CONFIG_DIR`, if any
+pub fn config_dir_override() -> Option<PathBuf> {
+    std::env::var_os(CONFIG_DIR_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
 }
 
+fn create_dir(path: PathBuf) -> io::Result<PathBuf> {
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
 
+fn not_found(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Cannot find the {} directory of the user", what),
+    )
+}
 
-pub fn get_config_path() -> PathBuf {
-    let mut path = dirs::config_dir().unwrap();
-    path.push("sbbw");
-    fs::create_dir_all(&path).unwrap();
-    path
+pub fn get_config_path() -> io::Result<PathBuf> {
+    let path = match config_dir_override() {
+        Some(dir) => std::path::absolute(dir)?,
+        None => dirs::config_dir().ok_or_else(|| not_found("config"))?.join("sbbw"),
+    };
+    create_dir(path)
+}
+pub fn get_widgets_path() -> io::Result<PathBuf> {
+    create_dir(get_config_path()?.join("widgets"))
 }
-pub fn get_widgets_path() -> PathBuf {
-    let mut path = get_config_path();
-    path.push("widgets");
-    fs::create_dir_all(&path).unwrap();
-    path
+///
+/// Directory of the widget logs, inside the config root when it is relocated
+/// so isolated setups don't mix their logs
+///
+pub fn get_logs_path() -> io::Result<PathBuf> {
+    let path = match config_dir_override() {
+        Some(_) => get_config_path()?.join("logs"),
+        None => dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .ok_or_else(|| not_found("state"))?
+            .join("sbbw")
+            .join("logs"),
+    };
+    create_dir(path)
 }
-pub fn get_widgets() -> Vec<String> {
-    let paths = fs::read_dir(get_widgets_path()).unwrap();
-    paths.filter_map(|path| {
-        let path = path.unwrap().path();
-        if path.is_dir() {
-            // if config file exist on folder
-            let mut config_path = path.clone();
-            config_path.push("config.toml");
-            if config_path.exists() {
-                Some(path.file_name().unwrap().to_str().unwrap().to_string())
-            } else {
-                None
+pub fn get_widgets() -> io::Result<Vec<String>> {
+    let mut widgets = Vec::new();
+    for entry in fs::read_dir(get_widgets_path()?)? {
+        let path = entry?.path();
+        // only folders with a config file are widgets
+        if path.is_dir() && path.join("config.toml").exists() {
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                widgets.push(name.to_string());
             }
-        } else {
-            None
         }
-    }).collect()
+    }
+    Ok(widgets)
+}
+
+/// Argument of `sbbw-widget` to print the id of each connected monitor, one per line
+pub const LIST_MONITORS_ARG: &str = "--list-monitors";
+
+/// Separator between the widget and the monitor on the key of an instance, like `bar@DP-1`
+pub const INSTANCE_SEPARATOR: char = '@';
+
+/// Key of the instance of `widget` opened on `monitor`
+pub fn instance_key(widget: &str, monitor: &str) -> String {
+    format!("{}{}{}", widget, INSTANCE_SEPARATOR, monitor)
+}
+
+/// Widget name and monitor of a key, the monitor is `None` for plain widget names
+pub fn split_instance(key: &str) -> (&str, Option<&str>) {
+    match key.split_once(INSTANCE_SEPARATOR) {
+        Some((widget, monitor)) => (widget, Some(monitor)),
+        None => (key, None),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
-    use crate::WidgetSize;
+    use std::time::Duration;
+
+    use crate::{
+        Anchor, ConfigError, Edge, Layer, Length, Margin, MonitorSelector, Rect, RestartPolicy,
+        WidgetSize, WindowType,
+    };
 
     #[test]
     fn test_validate_config_toml() {
@@ -204,10 +557,10 @@ mod tests {
         let conf = super::validate_config_toml(conf_path).unwrap();
         assert_eq!(conf.name, "Test");
         assert_eq!(conf.class_name, "Test_Class");
-        assert_eq!(conf.width, WidgetSize::Value(200.0));
+        assert_eq!(conf.width, WidgetSize::Value(Length::px(200.0)));
         assert_eq!(conf.height, WidgetSize::Max);
-        assert_eq!(conf.x, 0.0);
-        assert_eq!(conf.y, 0.0);
+        assert_eq!(conf.x, Length::px(0.0));
+        assert_eq!(conf.y, Length::px(0.0));
         assert_eq!(conf.transparent, true);
         assert_eq!(conf.blur, true);
         assert_eq!(conf.always_on_top, true);
@@ -230,10 +583,10 @@ mod tests {
 
         assert_eq!(conf.name, "Test");
         assert_eq!(conf.class_name, "Test_Class");
-        assert_eq!(conf.width, WidgetSize::Value(200.0));
-        assert_eq!(conf.height, WidgetSize::Value(300.0));
-        assert_eq!(conf.x, 0.0);
-        assert_eq!(conf.y, 0.0);
+        assert_eq!(conf.width, WidgetSize::Value(Length::px(200.0)));
+        assert_eq!(conf.height, WidgetSize::Value(Length::px(300.0)));
+        assert_eq!(conf.x, Length::px(0.0));
+        assert_eq!(conf.y, Length::px(0.0));
         assert_eq!(conf.transparent, true);
         assert_eq!(conf.blur, true);
         assert_eq!(conf.always_on_top, true);
@@ -252,12 +605,327 @@ mod tests {
 
         assert_eq!(conf.name, "Test");
         assert_eq!(conf.class_name, "Test Class");
-        assert_eq!(conf.width, WidgetSize::Value(200.0));
+        assert_eq!(conf.width, WidgetSize::Value(Length::px(200.0)));
         assert_eq!(conf.height, WidgetSize::Max);
-        assert_eq!(conf.x, 0.0);
-        assert_eq!(conf.y, 0.0);
+        assert_eq!(conf.x, Length::px(0.0));
+        assert_eq!(conf.y, Length::px(0.0));
         assert_eq!(conf.transparent, true);
         assert_eq!(conf.blur, true);
         assert_eq!(conf.always_on_top, true);
     }
+
+    #[test]
+    fn validate_restart_config() {
+        let raw_conf = r#"
+            name = "Test"
+            [restart]
+            policy = "on-failure"
+            max_retries = 2
+        "#;
+        let conf = super::validate_config_from_string(raw_conf).unwrap();
+
+        assert_eq!(conf.restart.policy, RestartPolicy::OnFailure);
+        assert_eq!(conf.restart.max_retries, 2);
+        assert_eq!(conf.restart.backoff, 1000);
+        assert!(conf.restart.should_restart(false, 0));
+        assert!(!conf.restart.should_restart(true, 0));
+        assert!(!conf.restart.should_restart(false, 2));
+        assert_eq!(conf.restart.backoff_for(0), Duration::from_secs(1));
+        assert_eq!(conf.restart.backoff_for(2), Duration::from_secs(4));
+        assert_eq!(conf.restart.backoff_for(30), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn config_dir_can_be_relocated() {
+        let root = std::env::temp_dir().join(format!("sbbw-test-config-{}", std::process::id()));
+        std::env::set_var(super::CONFIG_DIR_ENV, &root);
+
+        let widgets_path = super::get_widgets_path().unwrap();
+        assert_eq!(widgets_path, root.join("widgets"));
+        std::fs::create_dir_all(widgets_path.join("bar")).unwrap();
+        std::fs::write(widgets_path.join("bar").join("config.toml"), "name = \"bar\"").unwrap();
+        std::fs::create_dir_all(widgets_path.join("not-a-widget")).unwrap();
+        assert_eq!(super::get_widgets().unwrap(), vec!["bar".to_string()]);
+        assert_eq!(super::get_logs_path().unwrap(), root.join("logs"));
+
+        std::env::remove_var(super::CONFIG_DIR_ENV);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn config_errors_point_at_the_field() {
+        let raw_conf = "name = \"Test\"\n[restart]\nmax_retries = -1\n";
+        let error = match super::validate_config_from_string(raw_conf) {
+            Err(ConfigError::InvalidField(error)) => error,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let span = error.span.unwrap();
+        assert_eq!((span.line, span.column), (3, 15));
+        assert_eq!(span.field.as_deref(), Some("restart.max_retries"));
+
+        let raw_conf = "name = \"Test\"\nwidth = \"wide\"\n";
+        let error = match super::validate_config_from_string(raw_conf) {
+            Err(ConfigError::InvalidSize(error)) => error,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(
+            error.diagnostic(),
+            "error: Invalid size or position `wide`: expected a number at `wide`\n \
+             --> config.toml:2:9\n  |\n2 | width = \"wide\"\n  |         ^^^^^^ on field `width`\n"
+        );
+        // the kind does not leak into the next config loaded
+        assert!(matches!(
+            super::validate_config_from_string("opacity = 2"),
+            Err(ConfigError::InvalidField(_))
+        ));
+
+        assert!(matches!(
+            super::validate_config_from_string("name = \"Test"),
+            Err(ConfigError::Syntax(_))
+        ));
+        assert!(matches!(
+            super::validate_config_toml(PathBuf::from("/nonexistent/config.toml")),
+            Err(ConfigError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn sizes_resolve_against_the_monitor() {
+        let raw_conf = r#"
+            name = "Test"
+            width = "100% - 40px"
+            height = "20rem"
+            x = "20px"
+            y = 40
+        "#;
+        let conf = super::validate_config_from_string(raw_conf).unwrap();
+        match &conf.width {
+            WidgetSize::Value(width) => assert_eq!(width.to_string(), "100% - 40px"),
+            WidgetSize::Max => panic!("width should not be max"),
+        }
+
+        let monitor = Rect {
+            x: 1920.0,
+            y: 0.0,
+            width: 1920.0,
+            height: 1080.0,
+        };
+        let geometry = conf.geometry(monitor);
+        assert_eq!(geometry.x, 1940.0);
+        assert_eq!(geometry.y, 40.0);
+        assert_eq!(geometry.width, 1880.0);
+        assert_eq!(geometry.height, 320.0);
+
+        assert_eq!("50%".parse::<Length>().unwrap().resolve(3840.0), 1920.0);
+        assert_eq!("-10px + 1.5rem".parse::<Length>().unwrap().resolve(0.0), 14.0);
+        assert_eq!(WidgetSize::Max.resolve(1080.0), 1080.0);
+        assert!("100 % -".parse::<Length>().is_err());
+        assert!("20em".parse::<Length>().is_err());
+        assert!(matches!(
+            super::validate_config_from_string("width = \"wide\""),
+            Err(ConfigError::InvalidSize(_))
+        ));
+    }
+
+    #[test]
+    fn widgets_are_anchored_inside_the_margins() {
+        let work_area = Rect {
+            x: 0.0,
+            y: 30.0,
+            width: 1920.0,
+            height: 1050.0,
+        };
+
+        let sidebar = super::validate_config_from_string(
+            r#"
+            anchor = "right"
+            width = "20rem"
+            height = "max"
+            margin = { top = 10, bottom = 10, right = "1%" }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(sidebar.anchor, Anchor::CenterRight);
+        let geometry = sidebar.geometry(work_area);
+        assert_eq!(geometry.width, 320.0);
+        assert_eq!(geometry.height, 1030.0);
+        assert_eq!(geometry.x, 1920.0 - 19.2 - 320.0);
+        assert_eq!(geometry.y, 40.0);
+
+        let bottom_bar = super::validate_config_from_string(
+            r#"
+            anchor = "bottom-center"
+            width = "50%"
+            height = 40
+            margin = 8
+            y = -2
+        "#,
+        )
+        .unwrap();
+        assert_eq!(bottom_bar.margin, Margin::all(Length::px(8.0)));
+        let geometry = bottom_bar.geometry(work_area);
+        assert_eq!(geometry.width, 952.0);
+        assert_eq!(geometry.x, 8.0 + (1904.0 - 952.0) / 2.0);
+        assert_eq!(geometry.y, 30.0 + 1050.0 - 8.0 - 40.0 - 2.0);
+
+        assert!(matches!(
+            super::validate_config_from_string("anchor = \"middle\""),
+            Err(ConfigError::InvalidField(_))
+        ));
+        assert!(matches!(
+            super::validate_config_from_string("margin = \"wide\""),
+            Err(ConfigError::InvalidSize(_))
+        ));
+        assert!(matches!(
+            super::validate_config_from_string("margin = { top = \"wide\" }"),
+            Err(ConfigError::InvalidSize(_))
+        ));
+    }
+
+    #[test]
+    fn monitors_are_selected_by_name_or_index() {
+        let parse = |raw: &str| super::validate_config_from_string(raw).unwrap().monitor;
+        assert_eq!(parse(""), MonitorSelector::Current);
+        assert_eq!(parse("monitor = \"Primary\""), MonitorSelector::Primary);
+        assert_eq!(parse("monitor = 1"), MonitorSelector::Index(1));
+        assert_eq!(parse("monitor = \"DP-1\""), MonitorSelector::Name("DP-1".to_string()));
+        assert_eq!(parse("monitor = \"all\""), MonitorSelector::All);
+        assert!(super::validate_config_from_string("monitor = -1").is_err());
+
+        let key = super::instance_key("bar", "DP-1");
+        assert_eq!(key, "bar@DP-1");
+        assert_eq!(super::split_instance(&key), ("bar", Some("DP-1")));
+        assert_eq!(super::split_instance("bar"), ("bar", None));
+        assert_eq!(MonitorSelector::from_instance("2"), MonitorSelector::Index(2));
+    }
+
+    #[test]
+    fn bars_reserve_space_on_their_edge() {
+        let screen = Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 3840.0,
+            height: 1080.0,
+        };
+        let bar = super::validate_config_from_string(
+            r#"
+            window_type = "dock"
+            reserve_space = true
+            anchor = "bottom"
+            width = "100%"
+            height = 30
+            margin = { bottom = 4 }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(bar.window_type, WindowType::Dock);
+        let monitor = Rect {
+            x: 1920.0,
+            width: 1920.0,
+            ..screen
+        };
+        let geometry = bar.geometry(monitor);
+        assert_eq!(bar.reserved_edge(geometry), Some(Edge::Bottom));
+        let strut = Edge::Bottom.strut(geometry, screen);
+        assert_eq!(strut[..4], [0.0, 0.0, 0.0, 34.0]);
+        assert_eq!(strut[10..], [1920.0, 3839.0]);
+
+        let sidebar = Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 300.0,
+            height: 1080.0,
+        };
+        assert_eq!(Anchor::TopLeft.edge(sidebar), Some(Edge::Left));
+        assert_eq!(Anchor::Center.edge(sidebar), None);
+        let strut = Edge::Left.strut(sidebar, screen);
+        assert_eq!(strut[0], 300.0);
+        assert_eq!(strut[4..6], [0.0, 1079.0]);
+
+        assert_eq!(super::WidgetConfig::default().reserved_edge(sidebar), None);
+    }
+
+    #[test]
+    fn layer_surfaces_keep_their_margins() {
+        let conf = super::validate_config_from_string(
+            r#"
+            layer = "overlay"
+            exclusive_zone = -1
+            keyboard_interactivity = true
+            anchor = "bottom-right"
+            width = 300
+            height = 200
+            margin = 10
+            x = -5
+        "#,
+        )
+        .unwrap();
+        assert_eq!(conf.layer, Layer::Overlay);
+        assert_eq!(conf.exclusive_zone, Some(-1));
+        assert!(conf.keyboard_interactivity);
+        assert_eq!(Anchor::BottomRight.edges(), vec![Edge::Bottom, Edge::Right]);
+        assert!(Anchor::Center.edges().is_empty());
+
+        let monitor = Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 1920.0,
+            height: 1080.0,
+        };
+        let geometry = conf.geometry(monitor);
+        assert_eq!(Edge::Bottom.margin(geometry, monitor), 10.0);
+        assert_eq!(Edge::Right.margin(geometry, monitor), 15.0);
+        assert_eq!(Edge::Top.margin(geometry, monitor), 870.0);
+
+        let default = super::validate_config_from_string("").unwrap();
+        assert_eq!(default.layer, Layer::Top);
+        assert_eq!(default.exclusive_zone, None);
+    }
+
+    #[test]
+    fn class_name_is_derived_from_the_name() {
+        let conf = super::validate_config_from_string("name = \"My bar (top)\"").unwrap();
+        assert_eq!(conf.class_name, "MY_BAR__TOP_");
+        assert!(super::is_valid_class_name(&conf.class_name));
+        assert_eq!(super::WidgetConfig::new("clock".to_string()).class_name, "CLOCK");
+        assert_eq!(super::default_class_name("ñandú"), "_AND_");
+        assert_eq!(super::default_class_name(""), "SBBW");
+
+        assert!(super::is_valid_class_name("sbbw.bar-1"));
+        assert!(!super::is_valid_class_name("Test Class"));
+        assert!(!super::is_valid_class_name(""));
+    }
+
+    #[test]
+    fn widgets_stay_out_of_the_way_by_default() {
+        let conf = super::validate_config_from_string("").unwrap();
+        assert!(conf.skip_taskbar && conf.skip_pager);
+        assert!(conf.accept_focus);
+        assert!(!conf.focus_on_map);
+        assert_eq!(conf.opacity, 1.0);
+
+        let conf = super::validate_config_from_string(
+            r#"
+            skip_taskbar = false
+            focus_on_map = true
+            opacity = 0.8
+        "#,
+        )
+        .unwrap();
+        assert!(!conf.skip_taskbar);
+        assert!(conf.focus_on_map);
+        assert_eq!(conf.opacity, 0.8);
+        assert_eq!(
+            super::validate_config_from_string("opacity = 1").unwrap().opacity,
+            1.0
+        );
+
+        match super::validate_config_from_string("opacity = 1.5") {
+            Err(ConfigError::InvalidField(error)) => {
+                assert!(error.message.contains("between 0 and 1"));
+                assert_eq!(error.span.unwrap().field.as_deref(), Some("opacity"));
+            }
+            other => panic!("expected an invalid field, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,299 @@
+use crate::size::{Length, RawLength, Rect};
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt;
+
+///
+/// Point of the work area the widget is aligned to
+///
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    #[serde(alias = "top")]
+    TopCenter,
+    TopRight,
+    #[serde(alias = "left")]
+    CenterLeft,
+    Center,
+    #[serde(alias = "right")]
+    CenterRight,
+    BottomLeft,
+    #[serde(alias = "bottom")]
+    BottomCenter,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Fraction of the free space placed before the widget, horizontally and vertically
+    fn alignment(&self) -> (f64, f64) {
+        match self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::TopCenter => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::CenterLeft => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::CenterRight => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::BottomCenter => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+        }
+    }
+
+    ///
+    /// Position of a widget of `width` x `height` inside `area`,
+    /// before the offsets are applied
+    ///
+    pub fn place(&self, area: Rect, width: f64, height: f64) -> (f64, f64) {
+        let (horizontal, vertical) = self.alignment();
+        (
+            area.x + (area.width - width) * horizontal,
+            area.y + (area.height - height) * vertical,
+        )
+    }
+
+    /// Edges of the area the widget touches, none for `center`
+    pub fn edges(&self) -> Vec<Edge> {
+        let (horizontal, vertical) = self.alignment();
+        let mut edges = Vec::new();
+        if vertical == 0.0 {
+            edges.push(Edge::Top);
+        } else if vertical == 1.0 {
+            edges.push(Edge::Bottom);
+        }
+        if horizontal == 0.0 {
+            edges.push(Edge::Left);
+        } else if horizontal == 1.0 {
+            edges.push(Edge::Right);
+        }
+        edges
+    }
+
+    ///
+    /// Edge of the screen a widget placed at `geometry` sits on, corners
+    /// count as the top or bottom edge for wide widgets and the side for tall ones
+    ///
+    pub fn edge(&self, geometry: Rect) -> Option<Edge> {
+        let wide = geometry.width >= geometry.height;
+        match self {
+            Anchor::TopCenter => Some(Edge::Top),
+            Anchor::BottomCenter => Some(Edge::Bottom),
+            Anchor::CenterLeft => Some(Edge::Left),
+            Anchor::CenterRight => Some(Edge::Right),
+            Anchor::Center => None,
+            Anchor::TopLeft | Anchor::TopRight if wide => Some(Edge::Top),
+            Anchor::BottomLeft | Anchor::BottomRight if wide => Some(Edge::Bottom),
+            Anchor::TopLeft | Anchor::BottomLeft => Some(Edge::Left),
+            Anchor::TopRight | Anchor::BottomRight => Some(Edge::Right),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+impl Edge {
+    /// Distance between `widget` and this edge of `area`
+    pub fn margin(&self, widget: Rect, area: Rect) -> f64 {
+        match self {
+            Edge::Top => widget.y - area.y,
+            Edge::Bottom => area.y + area.height - widget.y - widget.height,
+            Edge::Left => widget.x - area.x,
+            Edge::Right => area.x + area.width - widget.x - widget.width,
+        }
+    }
+
+    ///
+    /// Values of `_NET_WM_STRUT_PARTIAL` reserving the space of `widget` on this edge,
+    /// `screen` is the area covering all the monitors. The first four values
+    /// (left, right, top and bottom) are also the ones of `_NET_WM_STRUT`
+    ///
+    pub fn strut(&self, widget: Rect, screen: Rect) -> [f64; 12] {
+        let mut strut = [0.0; 12];
+        let (size, start, length) = match self {
+            Edge::Left => (widget.x + widget.width - screen.x, widget.y, widget.height),
+            Edge::Right => (screen.x + screen.width - widget.x, widget.y, widget.height),
+            Edge::Top => (widget.y + widget.height - screen.y, widget.x, widget.width),
+            Edge::Bottom => (screen.y + screen.height - widget.y, widget.x, widget.width),
+        };
+        let (index, origin) = match self {
+            Edge::Left => (0, screen.y),
+            Edge::Right => (1, screen.y),
+            Edge::Top => (2, screen.x),
+            Edge::Bottom => (3, screen.x),
+        };
+        strut[index] = size.max(0.0);
+        strut[4 + index * 2] = start - origin;
+        strut[5 + index * 2] = start - origin + length - 1.0;
+        strut
+    }
+}
+
+///
+/// Space kept between the widget and the edges of the work area
+///
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Margin {
+    pub top: Length,
+    pub right: Length,
+    pub bottom: Length,
+    pub left: Length,
+}
+
+impl Margin {
+    pub fn all(length: Length) -> Self {
+        Margin {
+            top: length.clone(),
+            right: length.clone(),
+            bottom: length.clone(),
+            left: length,
+        }
+    }
+
+    /// Area left inside `area` once the margins are removed
+    pub fn shrink(&self, area: Rect) -> Rect {
+        let top = self.top.resolve(area.height);
+        let right = self.right.resolve(area.width);
+        let bottom = self.bottom.resolve(area.height);
+        let left = self.left.resolve(area.width);
+        Rect {
+            x: area.x + left,
+            y: area.y + top,
+            width: (area.width - left - right).max(0.0),
+            height: (area.height - top - bottom).max(0.0),
+        }
+    }
+}
+
+///
+/// A margin is either a single length used for all the edges,
+/// or a table with the `top`, `right`, `bottom` and `left` lengths
+///
+pub(crate) fn deserialize_margin<'de, D>(de: D) -> Result<Margin, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct MarginVisitor;
+
+    impl<'de> Visitor<'de> for MarginVisitor {
+        type Value = Margin;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a length or a table with top, right, bottom and left lengths")
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Margin, E> {
+            Ok(Margin::all(Length::px(value as f64)))
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Margin, E> {
+            Ok(Margin::all(Length::px(value as f64)))
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Margin, E> {
+            Ok(Margin::all(Length::px(value)))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Margin, E> {
+            RawLength::parse(value).map(Margin::all)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Margin, A::Error> {
+            Margin::deserialize(MapAccessDeserializer::new(map))
+        }
+    }
+
+    de.deserialize_any(MarginVisitor)
+}
+
+///
+/// Monitor where the widget is opened
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum MonitorSelector {
+    /// Wherever the window manager places the window
+    #[default]
+    Current,
+    Primary,
+    /// Position on the list of connected monitors, starting from 0
+    Index(usize),
+    /// Name reported by the system, like `DP-1`
+    Name(String),
+    /// One instance of the widget on each connected monitor
+    All,
+}
+
+impl MonitorSelector {
+    /// The selector written on an instance key, like `DP-1` on `bar@DP-1`
+    pub fn from_instance(instance: &str) -> Self {
+        match instance.parse() {
+            Ok(index) => MonitorSelector::Index(index),
+            Err(_) => MonitorSelector::Name(instance.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for MonitorSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MonitorSelector::Current => f.write_str("current"),
+            MonitorSelector::Primary => f.write_str("primary"),
+            MonitorSelector::Index(index) => write!(f, "{}", index),
+            MonitorSelector::Name(name) => f.write_str(name),
+            MonitorSelector::All => f.write_str("all"),
+        }
+    }
+}
+
+impl Serialize for MonitorSelector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MonitorSelector::Index(index) => serializer.serialize_u64(*index as u64),
+            selector => serializer.serialize_str(&selector.to_string()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MonitorSelector {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct SelectorVisitor;
+
+        impl<'de> Visitor<'de> for SelectorVisitor {
+            type Value = MonitorSelector;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a monitor index, a monitor name, \"primary\", \"current\" or \"all\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<MonitorSelector, E> {
+                Ok(MonitorSelector::Index(value as usize))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<MonitorSelector, E> {
+                usize::try_from(value)
+                    .map(MonitorSelector::Index)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<MonitorSelector, E> {
+                Ok(match value.to_lowercase().as_str() {
+                    "current" => MonitorSelector::Current,
+                    "primary" => MonitorSelector::Primary,
+                    "all" => MonitorSelector::All,
+                    _ => MonitorSelector::Name(value.to_string()),
+                })
+            }
+        }
+
+        de.deserialize_any(SelectorVisitor)
+    }
+}
@@ -0,0 +1,175 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{cell::Cell, fmt, str::FromStr};
+
+/// Logical pixels in a `rem`, the default font size of the webviews
+pub const REM_PX: f64 = 16.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    /// Logical pixels, also used for plain numbers
+    Px,
+    /// Percentage of the monitor width, or height for vertical values
+    Percent,
+    /// Multiple of `REM_PX`
+    Rem,
+}
+
+impl Unit {
+    fn suffix(&self) -> &'static str {
+        match self {
+            Unit::Px => "px",
+            Unit::Percent => "%",
+            Unit::Rem => "rem",
+        }
+    }
+}
+
+///
+/// Size or position like `300px`, `20rem`, `50%` or `100% - 40px`,
+/// resolved to logical pixels against a monitor dimension
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Length(Vec<(f64, Unit)>);
+
+impl Length {
+    pub fn px(value: f64) -> Self {
+        Length(vec![(value, Unit::Px)])
+    }
+
+    pub fn percent(value: f64) -> Self {
+        Length(vec![(value, Unit::Percent)])
+    }
+
+    /// Logical pixels of the length, where `available` is the monitor dimension it refers to
+    pub fn resolve(&self, available: f64) -> f64 {
+        self.0
+            .iter()
+            .map(|(value, unit)| match unit {
+                Unit::Px => *value,
+                Unit::Percent => available * value / 100.0,
+                Unit::Rem => value * REM_PX,
+            })
+            .sum()
+    }
+}
+
+impl Default for Length {
+    fn default() -> Self {
+        Length::px(0.0)
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut terms = Vec::new();
+        let mut rest = s.trim();
+        let mut sign = 1.0;
+        if let Some(unsigned) = rest.strip_prefix('-') {
+            sign = -1.0;
+            rest = unsigned.trim_start();
+        }
+        loop {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            let value = rest[..end]
+                .parse::<f64>()
+                .map_err(|_| format!("expected a number at `{}`", rest))?;
+            rest = &rest[end..];
+
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphabetic() || c == '%'))
+                .unwrap_or(rest.len());
+            let unit = match rest[..end].to_ascii_lowercase().as_str() {
+                "" | "px" => Unit::Px,
+                "%" => Unit::Percent,
+                "rem" => Unit::Rem,
+                unit => return Err(format!("unknown unit `{}`, expected px, % or rem", unit)),
+            };
+            terms.push((sign * value, unit));
+            rest = rest[end..].trim_start();
+
+            sign = match rest.chars().next() {
+                None => return Ok(Length(terms)),
+                Some('+') => 1.0,
+                Some('-') => -1.0,
+                Some(_) => return Err(format!("expected `+` or `-` at `{}`", rest)),
+            };
+            rest = rest[1..].trim_start();
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (value, unit)) in self.0.iter().enumerate() {
+            match i {
+                0 => write!(f, "{}{}", value, unit.suffix())?,
+                _ if *value < 0.0 => write!(f, " - {}{}", -value, unit.suffix())?,
+                _ => write!(f, " + {}{}", value, unit.suffix())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// Set when a length fails to parse, see `catch_length_errors`
+    static LENGTH_FAILED: Cell<bool> = const { Cell::new(false) };
+}
+
+///
+/// Run `parse` and tell if a length failed to parse on it, so the errors of
+/// sizes and positions are told apart without reading the serde message
+///
+pub(crate) fn catch_length_errors<T>(parse: impl FnOnce() -> T) -> (T, bool) {
+    LENGTH_FAILED.with(|failed| failed.set(false));
+    let result = parse();
+    (result, LENGTH_FAILED.with(|failed| failed.replace(false)))
+}
+
+/// Sizes and positions can be written as plain numbers, taken as pixels, or as strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum RawLength {
+    Number(f64),
+    Text(String),
+}
+
+impl RawLength {
+    /// Parse the text of a length, flagging the failures for `catch_length_errors`
+    pub(crate) fn parse<E: de::Error>(text: &str) -> Result<Length, E> {
+        text.parse().map_err(|e| {
+            LENGTH_FAILED.with(|failed| failed.set(true));
+            de::Error::custom(format!("Invalid size or position `{}`: {}", text, e))
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Length {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        match RawLength::deserialize(de)? {
+            RawLength::Number(value) => Ok(Length::px(value)),
+            RawLength::Text(text) => RawLength::parse(&text),
+        }
+    }
+}
+
+impl Serialize for Length {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+///
+/// Area in logical pixels, like a monitor or a widget window
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
@@ -6,7 +6,10 @@
     target_os = "openbsd",
     target_os = "netbsd"
 ))]
-use gtk::prelude::GtkWindowExt;
+use gtk::{
+    gdk,
+    prelude::{GtkWindowExt, WidgetExt},
+};
 
 #[cfg(any(
     target_os = "linux",
@@ -17,12 +20,56 @@ use gtk::prelude::GtkWindowExt;
 ))]
 use wry::application::platform::unix::WindowExtUnix;
 
-use wry::application::window::Window;
+use sbbw_widget_conf::{Edge, MonitorSelector, Rect, WidgetConfig, WindowType};
+use tracing::warn;
+use wry::application::{monitor::MonitorHandle, window::Window};
+
+///
+/// Set the WM_CLASS (instance and class) on X11 and the app_id on Wayland of the windows
+/// created from now on. Must be called after the event loop initialized gtk
+///
+pub fn set_app_class(class: &str) {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    {
+        // the instance and the app_id come from the program name, the class from the program class
+        gtk::glib::set_prgname(Some(class));
+        gdk::set_program_class(class);
+    }
+}
 
 pub trait ManagedWindow {
+    /// Set the role `{name}_{class}`, used by some window managers to tell windows of an app apart
     fn set_role(&self, name: &str, class: &str);
     // fn set_static_size(&self, width: i32, height: i32);
     fn stick(&self);
+    /// Let the mouse events go through the window, with an empty input shape
+    fn set_click_through(&self, enabled: bool);
+    /// Hide the window from taskbars and alt-tab lists, and from workspace pagers
+    fn set_taskbar_hints(&self, skip_taskbar: bool, skip_pager: bool);
+    /// Must be called before the window is shown, `focus_on_map` is only read on map
+    fn set_focus_policy(&self, accept_focus: bool, focus_on_map: bool);
+    /// Opacity of the whole window, needs a compositor on X11
+    fn set_window_opacity(&self, opacity: f64);
+    /// Must be called before the window is shown, most window managers read it only on map
+    fn set_window_type(&self, window_type: WindowType);
+    ///
+    /// Keep the space of the widget on `edge` out of the work area of the other windows.
+    /// `geometry` is in logical pixels of a monitor with `scale`. The struts are written
+    /// once the window is realized, tao only shows it on the next turn of the event loop
+    ///
+    fn reserve_space(&self, edge: Edge, geometry: Rect, scale: f64);
+    ///
+    /// Turn the window into a layer shell surface placed on `monitor`, before it is shown.
+    /// Returns false when the compositor is not Wayland or sbbw was built
+    /// without the `layer-shell` feature, then the window must be placed as usual
+    ///
+    fn init_layer_shell(&self, config: &WidgetConfig, monitor: &MonitorHandle) -> bool;
 }
 
 impl ManagedWindow for Window {
@@ -55,4 +102,358 @@ impl ManagedWindow for Window {
         #[cfg(target_os = "macos")]
         {}
     }
+
+    fn set_click_through(&self, enabled: bool) {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
+        {
+            let gtk_win = self.gtk_window();
+            if enabled {
+                gtk_win.input_shape_combine_region(Some(&gtk::cairo::Region::create()));
+            } else {
+                gtk_win.input_shape_combine_region(None);
+            }
+        }
+    }
+
+    fn set_taskbar_hints(&self, skip_taskbar: bool, skip_pager: bool) {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
+        {
+            let gtk_win = self.gtk_window();
+            gtk_win.set_skip_taskbar_hint(skip_taskbar);
+            gtk_win.set_skip_pager_hint(skip_pager);
+        }
+    }
+
+    fn set_focus_policy(&self, accept_focus: bool, focus_on_map: bool) {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
+        {
+            let gtk_win = self.gtk_window();
+            gtk_win.set_accept_focus(accept_focus);
+            gtk_win.set_focus_on_map(focus_on_map);
+        }
+    }
+
+    fn set_window_opacity(&self, opacity: f64) {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
+        {
+            self.gtk_window().set_opacity(opacity);
+        }
+    }
+
+    fn set_window_type(&self, window_type: WindowType) {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
+        {
+            let hint = match window_type {
+                WindowType::Normal => gdk::WindowTypeHint::Normal,
+                WindowType::Dock => gdk::WindowTypeHint::Dock,
+                WindowType::Desktop => gdk::WindowTypeHint::Desktop,
+                WindowType::Notification => gdk::WindowTypeHint::Notification,
+                WindowType::Splash => gdk::WindowTypeHint::Splashscreen,
+            };
+            self.gtk_window().set_type_hint(hint);
+        }
+    }
+
+    fn reserve_space(&self, edge: Edge, geometry: Rect, scale: f64) {
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
+        {
+            // struts are relative to the root window, which spans all the monitors in device pixels
+            let screen = self
+                .available_monitors()
+                .map(|monitor| Rect {
+                    x: monitor.position().x as f64,
+                    y: monitor.position().y as f64,
+                    width: monitor.size().width as f64,
+                    height: monitor.size().height as f64,
+                })
+                .reduce(|a, b| {
+                    let x = a.x.min(b.x);
+                    let y = a.y.min(b.y);
+                    Rect {
+                        x,
+                        y,
+                        width: (a.x + a.width).max(b.x + b.width) - x,
+                        height: (a.y + a.height).max(b.y + b.height) - y,
+                    }
+                })
+                .unwrap_or_default();
+            let widget = Rect {
+                x: geometry.x * scale,
+                y: geometry.y * scale,
+                width: geometry.width * scale,
+                height: geometry.height * scale,
+            };
+            let strut: Vec<std::os::raw::c_ulong> = edge
+                .strut(widget, screen)
+                .iter()
+                .map(|value| value.round().max(0.0) as std::os::raw::c_ulong)
+                .collect();
+            match self.gtk_window().window() {
+                Some(gdk_window) => set_struts(&gdk_window, &strut),
+                None => {
+                    self.gtk_window().connect_realize(move |gtk_window| {
+                        match gtk_window.window() {
+                            Some(gdk_window) => set_struts(&gdk_window, &strut),
+                            None => warn!(
+                                target: "widget",
+                                "Window realized without a gdk window, cannot reserve space"
+                            ),
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    fn init_layer_shell(&self, config: &WidgetConfig, monitor: &MonitorHandle) -> bool {
+        #[cfg(all(
+            feature = "layer-shell",
+            any(
+                target_os = "linux",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd"
+            )
+        ))]
+        {
+            use gtk::prelude::ObjectExt;
+            use gtk_layer_shell as layer_shell;
+            use sbbw_widget_conf::Layer;
+            use wry::application::dpi::{LogicalSize, Size};
+
+            let is_wayland = gdk::Display::default()
+                .is_some_and(|display| display.type_().name() == "GdkWaylandDisplay");
+            // compositors without wlr-layer-shell keep the regular window placement
+            if is_wayland && layer_shell::is_supported() {
+                let gtk_win = self.gtk_window();
+                layer_shell::init_for_window(gtk_win);
+                layer_shell::set_namespace(gtk_win, &config.class_name);
+                if let Some(gdk_monitor) = gdk_monitor(monitor) {
+                    layer_shell::set_monitor(gtk_win, &gdk_monitor);
+                }
+                layer_shell::set_layer(
+                    gtk_win,
+                    match config.layer {
+                        Layer::Background => layer_shell::Layer::Background,
+                        Layer::Bottom => layer_shell::Layer::Bottom,
+                        Layer::Top => layer_shell::Layer::Top,
+                        Layer::Overlay => layer_shell::Layer::Overlay,
+                    },
+                );
+
+                // the compositor keeps the surface on the anchored edges, at the margins from them
+                let area = work_area(monitor);
+                let geometry = config.geometry(area);
+                let anchors = config.anchor.edges();
+                for edge in [Edge::Top, Edge::Bottom, Edge::Left, Edge::Right] {
+                    let spans = match edge {
+                        Edge::Top | Edge::Bottom => geometry.height >= area.height,
+                        Edge::Left | Edge::Right => geometry.width >= area.width,
+                    };
+                    if !spans && !anchors.contains(&edge) {
+                        continue;
+                    }
+                    let layer_edge = match edge {
+                        Edge::Top => layer_shell::Edge::Top,
+                        Edge::Bottom => layer_shell::Edge::Bottom,
+                        Edge::Left => layer_shell::Edge::Left,
+                        Edge::Right => layer_shell::Edge::Right,
+                    };
+                    layer_shell::set_anchor(gtk_win, layer_edge, true);
+                    layer_shell::set_margin(
+                        gtk_win,
+                        layer_edge,
+                        edge.margin(geometry, area).round() as i32,
+                    );
+                }
+                match config.exclusive_zone {
+                    Some(zone) => layer_shell::set_exclusive_zone(gtk_win, zone),
+                    None if config.reserve_space => {
+                        layer_shell::auto_exclusive_zone_enable(gtk_win)
+                    }
+                    None => {}
+                }
+                layer_shell::set_keyboard_interactivity(gtk_win, config.keyboard_interactivity);
+                self.set_inner_size(Size::Logical(LogicalSize::new(
+                    geometry.width,
+                    geometry.height,
+                )));
+                return true;
+            }
+        }
+        let _ = (config, monitor);
+        false
+    }
+}
+
+/// Area of a monitor in logical pixels
+fn monitor_rect(monitor: &MonitorHandle) -> Rect {
+    let scale = monitor.scale_factor();
+    let position = monitor.position().to_logical::<f64>(scale);
+    let size = monitor.size().to_logical::<f64>(scale);
+    Rect {
+        x: position.x,
+        y: position.y,
+        width: size.width,
+        height: size.height,
+    }
+}
+
+/// Write the `_NET_WM_STRUT_PARTIAL` of the window, and `_NET_WM_STRUT` with its first four values
+#[cfg(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+fn set_struts(gdk_window: &gdk::Window, strut: &[std::os::raw::c_ulong]) {
+    let cardinal = gdk::Atom::intern("CARDINAL");
+    gdk::property_change(
+        gdk_window,
+        &gdk::Atom::intern("_NET_WM_STRUT_PARTIAL"),
+        &cardinal,
+        32,
+        gdk::PropMode::Replace,
+        gdk::ChangeData::ULongs(strut),
+    );
+    // for window managers without support of the partial one
+    gdk::property_change(
+        gdk_window,
+        &gdk::Atom::intern("_NET_WM_STRUT"),
+        &cardinal,
+        32,
+        gdk::PropMode::Replace,
+        gdk::ChangeData::ULongs(&strut[..4]),
+    );
+}
+
+/// The gdk monitor of a tao one, gdk coordinates are logical too so it is found by its origin
+#[cfg(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+fn gdk_monitor(monitor: &MonitorHandle) -> Option<gdk::Monitor> {
+    let area = monitor_rect(monitor);
+    gdk::Display::default()?.monitor_at_point(area.x as i32, area.y as i32)
+}
+
+///
+/// Part of a monitor not covered by panels and docks, in logical pixels,
+/// sizes and positions of the widgets are relative to it.
+/// Only known on the gtk platforms, elsewhere the whole monitor is used
+///
+pub fn work_area(monitor: &MonitorHandle) -> Rect {
+    let area = monitor_rect(monitor);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    {
+        if let Some(gdk_monitor) = gdk_monitor(monitor) {
+            let workarea = gdk_monitor.workarea();
+            return Rect {
+                x: workarea.x() as f64,
+                y: workarea.y() as f64,
+                width: workarea.width() as f64,
+                height: workarea.height() as f64,
+            };
+        }
+    }
+    area
+}
+
+/// Id of a monitor on the instance keys, its name or its index when the system gives none
+pub fn monitor_id(index: usize, monitor: &MonitorHandle) -> String {
+    monitor.name().unwrap_or_else(|| index.to_string())
+}
+
+///
+/// Monitor picked by `selector`, `None` when it is not connected.
+/// `All` is resolved by the daemon into one instance per monitor, so here it means the current one
+///
+pub fn select_monitor(window: &Window, selector: &MonitorSelector) -> Option<MonitorHandle> {
+    match selector {
+        MonitorSelector::Current | MonitorSelector::All => current_monitor(window),
+        MonitorSelector::Primary => window.primary_monitor(),
+        MonitorSelector::Index(index) => window.available_monitors().nth(*index),
+        MonitorSelector::Name(name) => window
+            .available_monitors()
+            .enumerate()
+            .find(|(index, monitor)| monitor_id(*index, monitor) == *name)
+            .map(|(_, monitor)| monitor),
+    }
+}
+
+///
+/// Monitor of the window. Before it is shown the window has no gdk window to ask,
+/// so the monitor under the pointer is used, where most window managers open new windows
+///
+pub fn current_monitor(window: &Window) -> Option<MonitorHandle> {
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    if window.gtk_window().window().is_none() {
+        let under_pointer = gdk::Display::default().and_then(|display| {
+            let (_, x, y) = display.default_seat()?.pointer()?.position();
+            display.monitor_at_point(x, y)
+        });
+        return under_pointer
+            .and_then(|gdk_monitor| {
+                let geometry = gdk_monitor.geometry();
+                window.available_monitors().find(|monitor| {
+                    let area = monitor_rect(monitor);
+                    area.x as i32 == geometry.x() && area.y as i32 == geometry.y()
+                })
+            })
+            .or_else(|| window.primary_monitor());
+    }
+    window.current_monitor()
 }
@@ -0,0 +1,19 @@
+use sbbw_widget_conf::LOG_ENV;
+use std::io::{self, IsTerminal};
+use tracing_subscriber::EnvFilter;
+
+///
+/// Setup the logger of the widget, writing to stderr with the filter of `SBBW_LOG`
+///
+/// The daemon exports its log level there and timestamps the output of its widgets,
+/// so no time is written here
+///
+pub fn init() {
+    let filter = EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .without_time()
+        .init();
+}
@@ -5,6 +5,7 @@
     windows_subsystem = "windows"
 )]
 mod exts;
+mod logging;
 use exts::*;
 use serde::{Deserialize, Serialize};
 use tao::window::WindowId;
@@ -15,20 +16,26 @@ use std::{
     collections::HashMap,
     env,
     fs::{self, File},
+    io::{self, BufRead},
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use colored::*;
-use sbbw_widget_conf::{get_widgets, get_widgets_path, WidgetSize};
-use sbbw_exec::{Params, exec_command};
+use sbbw_widget_conf::{
+    get_widgets, get_widgets_path, is_valid_class_name, split_instance, Margin,
+    MonitorSelector, WidgetCommand, WidgetSize, CONFIG_DIR_ENV, LIST_MONITORS_ARG,
+};
+use sbbw_exec::{CancelToken, ExecError, ExecPool, ExecResult, Params, exec_command};
 use tauri_plugin_vibrancy::Vibrancy;
+use tracing::{debug, error, info, warn};
 
 use wry::{
     application::{
         dpi::{LogicalPosition, LogicalSize, Position, Size},
         event::{Event, WindowEvent},
-        event_loop::{ControlFlow, EventLoop},
+        event_loop::{ControlFlow, EventLoop, EventLoopProxy},
         window::{Fullscreen, Window, WindowBuilder},
     },
     http::{
@@ -43,20 +50,150 @@ use wry::{
 #[derive(Serialize)]
 struct SbbwResponse {
     pub status: u16,
-    pub data: String,
+    /// An `ExecResult` for `exec`, or the error message
+    pub data: Value,
+}
+
+/// Time given to the page to handle `beforeclose` before the widget exits
+const BEFORE_CLOSE_GRACE: Duration = Duration::from_millis(300);
+
+///
+/// Grace before exit, kept under half of `shutdown_timeout` so the daemon
+/// does not kill the widget while the page is still cleaning up
+///
+fn before_close_grace(shutdown_timeout: u64) -> Duration {
+    BEFORE_CLOSE_GRACE.min(Duration::from_millis(shutdown_timeout / 2))
+}
+
+/// Status of the calls stopped by `rpc.cancel`, like the "client closed request" of nginx
+const CANCELLED: u16 = 499;
+
+enum UserEvent {
+    Daemon(WidgetCommand),
+    /// Response of an `exec` call, by its `method_id`, once the command finished
+    Exec(u32, SbbwResponse),
+}
+
+///
+/// Response sent to the page for a command, a failed command rejects
+/// the promise with its result so the page can read the stderr
+///
+fn exec_response(result: Result<ExecResult, ExecError>) -> SbbwResponse {
+    match result {
+        Ok(result) => {
+            let status = if result.success() {
+                StatusCode::OK
+            } else {
+                debug!(target: "widget", code = ?result.code, "Command failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            SbbwResponse {
+                status: status.as_u16(),
+                data: serde_json::to_value(result).unwrap(),
+            }
+        }
+        Err(e) => {
+            let status = match e {
+                ExecError::Forbidden(_) => StatusCode::FORBIDDEN.as_u16(),
+                ExecError::Spawn(_, _) => StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                ExecError::TimedOut(_, _) => StatusCode::GATEWAY_TIMEOUT.as_u16(),
+                ExecError::Cancelled(_) => CANCELLED,
+            };
+            if status != CANCELLED {
+                warn!(target: "widget", "{}", e);
+            }
+            SbbwResponse {
+                status,
+                data: e.to_string().into(),
+            }
+        }
+    }
+}
+
+/// Script settling the promise of the call `method_id` on the page
+fn result_script(method_id: u32, response: &SbbwResponse) -> String {
+    format!(
+        "window.external.rpc._result({}, {})",
+        method_id,
+        serde_json::to_string(response).unwrap()
+    )
+}
+
+///
+/// Forward the commands sent by the daemon through stdin to the event loop
+///
+fn listen_daemon(proxy: EventLoopProxy<UserEvent>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match serde_json::from_str::<WidgetCommand>(&line) {
+                Ok(command) => {
+                    if proxy.send_event(UserEvent::Daemon(command)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!(target: "widget", "Invalid command from daemon: {}", e),
+            }
+        }
+    });
+}
+
+///
+/// Take `--config-dir` out of the arguments, given like on `sbbw`,
+/// so the widget name and the url keep their positions
+///
+fn take_config_dir_arg(args: &mut Vec<String>) -> Option<String> {
+    let index = args
+        .iter()
+        .position(|arg| arg == "--config-dir" || arg.starts_with("--config-dir="))?;
+    let arg = args.remove(index);
+    match arg.strip_prefix("--config-dir=") {
+        Some(dir) => Some(dir.to_string()),
+        None if index < args.len() => Some(args.remove(index)),
+        None => None,
+    }
 }
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
+    logging::init();
+    let mut args: Vec<_> = env::args().collect();
+    if let Some(dir) = take_config_dir_arg(&mut args) {
+        env::set_var(CONFIG_DIR_ENV, dir);
+    }
     // println!("{:?}", args.len());
     // println!("{:?}", args);
+    if args.get(1).map(String::as_str) == Some(LIST_MONITORS_ARG) {
+        let event_loop = EventLoop::new();
+        for (index, monitor) in event_loop.available_monitors().enumerate() {
+            println!("{}", monitor_id(index, &monitor));
+        }
+        return;
+    }
     if args.len() > 1 {
-        let widgets = get_widgets();
-        if widgets.contains(&args[1]) {
-            let widget_name = args[1].to_string();
-            let path_to_widget_conf = get_widgets_path().join(&widget_name).join("config.toml");
-            let path_scripts = get_widgets_path().join(&widget_name).join("scripts");
-            let widget_conf = sbbw_widget_conf::validate_config_toml(path_to_widget_conf).unwrap();
+        let widgets_path = match get_widgets_path() {
+            Ok(path) => path,
+            Err(e) => {
+                error!(target: "widget", "Cannot use the config directory: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let widgets = get_widgets().unwrap_or_default();
+        // instances opened on each monitor are named like `bar@DP-1`
+        let (widget_name, instance) = split_instance(&args[1]);
+        if widgets.iter().any(|widget| widget == widget_name) {
+            let widget_name = widget_name.to_string();
+            let path_to_widget_conf = widgets_path.join(&widget_name).join("config.toml");
+            let path_scripts = widgets_path.join(&widget_name).join("scripts");
+            let widget_conf = match sbbw_widget_conf::validate_config_toml(path_to_widget_conf) {
+                Ok(widget_conf) => widget_conf,
+                Err(e) => {
+                    error!(target: "widget", "{}", e);
+                    std::process::exit(1);
+                }
+            };
             let mut is_testing = false;
             let url_ui = if args.len() == 3 {
                 if args[2].contains("http") {
@@ -68,7 +205,7 @@ fn main() {
             } else {
                 format!("http://localhost:8000/{}/ui", widget_name)
             };
-            // println!("{:?}", url_ui);
+            info!(target: "widget", widget = %widget_name, url = %url_ui, "Loading widget");
             let widget_conf_clone = widget_conf.clone();
 
             // let widget_scripts_vec: Vec<String> = fs::read_dir(path_scripts)
@@ -85,39 +222,81 @@ fn main() {
             //     })
             //     .collect();
 
-            let event_loop = EventLoop::new();
+            let event_loop = EventLoop::<UserEvent>::with_user_event();
+            listen_daemon(event_loop.create_proxy());
+            let proxy = event_loop.create_proxy();
+            let exec_conf = Arc::new(widget_conf.clone());
+            let pool = ExecPool::new(widget_conf.exec.concurrency);
+            // commands of the page still running, by the id of their call
+            let running = Arc::new(Mutex::new(HashMap::<u32, CancelToken>::new()));
+            if !is_valid_class_name(&widget_conf.class_name) {
+                warn!(
+                    target: "widget",
+                    class_name = %widget_conf.class_name,
+                    "Class name has characters that window rules may not match"
+                );
+            }
+            set_app_class(&widget_conf.class_name);
             let window = WindowBuilder::new()
                 .with_decorations(false)
                 .with_title(&widget_conf.name)
                 .with_always_on_top(widget_conf.always_on_top)
-                .with_position(Position::Logical(LogicalPosition::new(
-                    widget_conf.x as f64,
-                    widget_conf.y as f64,
-                )))
                 .with_transparent(widget_conf.transparent)
+                // shown once the window manager hints are set
+                .with_visible(false)
                 .build(&event_loop)
                 .unwrap();
+            window.set_window_type(widget_conf.window_type);
 
-            if widget_conf.width == WidgetSize::Max && widget_conf.height == WidgetSize::Max {
-                window.set_fullscreen(Some(Fullscreen::Borderless(window.current_monitor())));
-            } else {
-                let monitor_size = &window.current_monitor().unwrap().size();
-                let width = match widget_conf_clone.width {
-                    WidgetSize::Max => monitor_size.width as f64,
-                    WidgetSize::Value(v) => v,
-                };
-                let height = match widget_conf_clone.height {
-                    WidgetSize::Max => monitor_size.height as f64,
-                    WidgetSize::Value(v) => v,
-                };
-                window.set_inner_size(Size::Logical(LogicalSize::new(width, height)))
-            }
+            let selector = instance
+                .map(MonitorSelector::from_instance)
+                .unwrap_or_else(|| widget_conf.monitor.clone());
+            let monitor = select_monitor(&window, &selector).or_else(|| {
+                warn!(
+                    target: "widget",
+                    monitor = %selector,
+                    "Monitor not found, using the current one"
+                );
+                current_monitor(&window)
+            });
+            let is_fullscreen = widget_conf.width == WidgetSize::Max
+                && widget_conf.height == WidgetSize::Max
+                && widget_conf.margin == Margin::default();
+            // the compositor places layer shell surfaces and keeps their exclusive zone itself
+            let placed = match monitor {
+                Some(monitor) if window.init_layer_shell(&widget_conf, &monitor) => None,
+                monitor if is_fullscreen => {
+                    window.set_fullscreen(Some(Fullscreen::Borderless(monitor)));
+                    None
+                }
+                Some(monitor) => {
+                    let geometry = widget_conf.geometry(work_area(&monitor));
+                    window.set_inner_size(Size::Logical(LogicalSize::new(
+                        geometry.width,
+                        geometry.height,
+                    )));
+                    window.set_outer_position(Position::Logical(LogicalPosition::new(
+                        geometry.x,
+                        geometry.y,
+                    )));
+                    Some((geometry, monitor.scale_factor()))
+                }
+                None => None,
+            };
 
             window.set_role(&widget_conf_clone.name, &widget_conf_clone.class_name);
             // window.set_resizable(false).unwrap();
             if widget_conf_clone.stick {
                 window.stick();
             }
+            if widget_conf_clone.click_through {
+                window.set_click_through(true);
+            }
+            window.set_taskbar_hints(widget_conf.skip_taskbar, widget_conf.skip_pager);
+            window.set_focus_policy(widget_conf.accept_focus, widget_conf.focus_on_map);
+            if widget_conf.opacity < 1.0 {
+                window.set_window_opacity(widget_conf.opacity);
+            }
             if widget_conf_clone.blur {
                 #[cfg(target_os = "windows")]
                 window.apply_acrylic();
@@ -127,6 +306,17 @@ fn main() {
                     window.apply_vibrancy(tauri_plugin_vibrancy::MacOSVibrancy::AppearanceBased);
                 }
             }
+            window.set_visible(true);
+            if let Some((geometry, scale)) = placed {
+                match widget_conf.reserved_edge(geometry) {
+                    Some(edge) => window.reserve_space(edge, geometry, scale),
+                    None if widget_conf.reserve_space => warn!(
+                        target: "widget",
+                        "Centered widgets cannot reserve space, anchor it to an edge"
+                    ),
+                    None => {}
+                }
+            }
 
             thread_local! {
                 static WEBVIEWS: RefCell<Option<WebView>> = RefCell::new(None);
@@ -160,22 +350,39 @@ fn main() {
             }
         }
 
-        this.call = function(cmd, args) {
+        this._send = function(method, command, args, options) {
             let array = new Uint32Array(1);
             window.crypto.getRandomValues(array);
             const id = array[0];
-            const payload = {
+            const payload = Object.assign({
                 method_id: id,
-                method: "exec",
-                command: cmd,
+                method,
+                command,
                 args,
-            };
+            }, options);
             const promise = new Promise((resolve, reject) => {
                 self._promises[id] = {resolve, reject};
             });
+            // used by rpc.cancel
+            promise.id = id;
             window.ipc.postMessage(JSON.stringify(payload));
             return promise;
         }
+
+        // options.timeout: milliseconds before the command is killed, 0 to wait forever
+        this.call = function(cmd, args, options) {
+            return this._send("exec", cmd, args, { timeout: options && options.timeout });
+        }
+
+        // kill a running command, its call is rejected with code 499
+        this.cancel = function(id) {
+            return this._send("cancel", "", [String(id)]);
+        }
+
+        // let the mouse events go through the widget, or catch them again
+        this.setClickThrough = function(enabled) {
+            return this._send("click_through", "", [String(!!enabled)]);
+        }
     }
     window.external = window.external || {};
     window.external.rpc = new Rpc();
@@ -183,55 +390,91 @@ fn main() {
 })();
                 "#,
                 )
-                .with_ipc_handler(move |_win, msg| {
+                .with_ipc_handler(move |win, msg| {
                     let mut response = SbbwResponse {
                         status: StatusCode::OK.as_u16(),
-                        data: "{}".to_string(),
+                        data: Value::Object(Default::default()),
                     };
                     let params: Option<Params> =
                         if let Ok(params) = serde_json::from_str(msg.as_str()) {
                             Some(params)
                         } else {
                             response.status = StatusCode::BAD_REQUEST.as_u16();
-                            response.data = "Invalid JSON sended".to_string();
+                            response.data = "Invalid JSON sended".into();
                             Some(Params {
                                 method_id: 0,
                                 method: "".to_string(),
                                 command: "".to_string(),
                                 args: Vec::new(),
+                                timeout: None,
                             })
                         };
 
                     let method = &params.as_ref().unwrap().method;
                     let params_clone = Some(params.as_ref().unwrap().clone());
+                    debug!(target: "widget", %method, "RPC call");
                     if method.is_empty() {
                         response.status = StatusCode::NOT_FOUND.as_u16();
-                        response.data = "Invalid command".to_string();
+                        response.data = "Invalid command".into();
                     } else {
                         if method.trim().eq("exec") {
                             if response.status == StatusCode::OK {
-                                response.status = StatusCode::OK.as_u16();
-                                response.data = exec_command(
-                                    String::from(path_scripts.to_str().unwrap()),
-                                    params.unwrap().clone(),
-                                ).unwrap();
+                                // run off the event loop, the page is answered once it finishes
+                                // and the call waits on the pool while it is full
+                                let params = params.unwrap();
+                                let method_id = params.method_id;
+                                let cancel = CancelToken::default();
+                                running.lock().unwrap().insert(method_id, cancel.clone());
+                                let pwd = String::from(path_scripts.to_str().unwrap());
+                                let exec_conf = exec_conf.clone();
+                                let running = running.clone();
+                                let proxy = proxy.clone();
+                                pool.execute(move || {
+                                    let result = exec_command(pwd, params, &exec_conf, &cancel);
+                                    running.lock().unwrap().remove(&method_id);
+                                    proxy
+                                        .send_event(UserEvent::Exec(
+                                            method_id,
+                                            exec_response(result),
+                                        ))
+                                        .ok();
+                                });
+                                return;
+                            }
+                        } else if method.trim().eq("cancel") {
+                            let cancel = params
+                                .as_ref()
+                                .unwrap()
+                                .args
+                                .first()
+                                .and_then(|id| id.parse::<u32>().ok())
+                                .and_then(|id| running.lock().unwrap().get(&id).cloned());
+                            match cancel {
+                                Some(cancel) => cancel.cancel(),
+                                None => {
+                                    response.status = StatusCode::NOT_FOUND.as_u16();
+                                    response.data = "No command running with that id".into();
+                                }
+                            }
+                        } else if method.trim().eq("click_through") {
+                            match params.as_ref().unwrap().args.first().map(String::as_str) {
+                                Some("true") => win.set_click_through(true),
+                                Some("false") => win.set_click_through(false),
+                                _ => {
+                                    response.status = StatusCode::BAD_REQUEST.as_u16();
+                                    response.data = "Expected true or false".into();
+                                }
                             }
                         } else {
+                            warn!(target: "widget", %method, "Unknown RPC method");
                             response.status = StatusCode::NOT_FOUND.as_u16();
-                            response.data =
-                                format!("Command \"{}\" not found", &method).to_string();
+                            response.data = format!("Command \"{}\" not found", &method).into();
                         };
                     }
                     WEBVIEWS.with(|ref_webview| {
                         let webviews = ref_webview.borrow();
                         let webview = webviews.as_ref().unwrap();
-                        let response_json = serde_json::to_string(&response).unwrap();
-                        // println!("response: {}", &response_json);
-                        let js = format!(r#"
-window.external.rpc._result({}, {})"#,
-                            params_clone.unwrap().method_id,
-                            response_json
-                        );
+                        let js = result_script(params_clone.unwrap().method_id, &response);
                         webview.evaluate_script(js.as_str()).unwrap();
                     });
                 })
@@ -248,19 +491,59 @@ window.external.rpc._result({}, {})"#,
                 ref_webview.replace(Some(webview));
             });
 
+            let close_grace = before_close_grace(widget_conf.shutdown_timeout);
+            let mut closing_at: Option<Instant> = None;
             event_loop.run(move |event, _, control_flow| {
-                *control_flow = ControlFlow::Wait;
-
                 match event {
+                    Event::UserEvent(UserEvent::Exec(method_id, response)) => {
+                        WEBVIEWS.with(|ref_webview| {
+                            if let Some(webview) = ref_webview.borrow().as_ref() {
+                                let js = result_script(method_id, &response);
+                                webview.evaluate_script(js.as_str()).ok();
+                            }
+                        });
+                    }
+                    Event::UserEvent(UserEvent::Daemon(WidgetCommand::Reload)) => {
+                        info!(target: "widget", "Reloading the page");
+                        WEBVIEWS.with(|ref_webview| {
+                            if let Some(webview) = ref_webview.borrow().as_ref() {
+                                webview.evaluate_script("window.location.reload()").ok();
+                            }
+                        });
+                    }
+                    Event::UserEvent(UserEvent::Daemon(WidgetCommand::Shutdown))
+                    | Event::WindowEvent {
+                        event: WindowEvent::CloseRequested,
+                        ..
+                    } => {
+                        if closing_at.is_none() {
+                            // give the page a chance to clean up before exit
+                            WEBVIEWS.with(|ref_webview| {
+                                if let Some(webview) = ref_webview.borrow().as_ref() {
+                                    webview
+                                        .evaluate_script(
+                                            "window.dispatchEvent(new Event('beforeclose'))",
+                                        )
+                                        .ok();
+                                }
+                            });
+                            closing_at = Some(Instant::now() + close_grace);
+                        }
+                    }
                     _ => {}
                 }
+
+                *control_flow = match closing_at {
+                    Some(at) if Instant::now() >= at => ControlFlow::Exit,
+                    Some(at) => ControlFlow::WaitUntil(at),
+                    None => ControlFlow::Wait,
+                };
             });
         } else {
-            println!(
-                "{}",
-                "Widget {} not found or not have config.toml file"
-                    .red()
-                    .replace("{}", &args[1].yellow().bold())
+            error!(
+                target: "widget",
+                widget = %args[1],
+                "Widget not found or not have config.toml file"
             );
             return;
         }
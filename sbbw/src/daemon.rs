@@ -1,33 +1,242 @@
-use colored::Colorize;
-use serde::Serialize;
+use crate::{
+    logs,
+    transport::{ControlStream, Transport},
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    io::{Read, Write},
-    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::Shutdown,
     rc::Rc,
 };
+use tracing::{debug, error, info, warn};
 
-type TransferDataFunc = dyn Fn(TransferData);
+/// Version of the messages exchanged between `sbbw` clients and the daemon.
+/// Bump it on every incompatible change to `TransferData` or `DaemonCommand`.
+pub const PROTOCOL_VERSION: u32 = 1;
 
-#[allow(dead_code)]
-#[derive(Serialize)]
-pub enum TransferData {
-    Get((String, String)),
-    Set((String, String)),
+type TransferDataFunc = dyn Fn(DaemonCommand) -> DaemonResponse;
+type ListenFunc = dyn Fn();
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "command", content = "args", rename_all = "lowercase")]
+pub enum DaemonCommand {
+    Open {
+        widget: String,
+    },
+    Close {
+        widget: String,
+    },
+    Toggle {
+        widget: String,
+    },
+    Test {
+        widget: String,
+        url: String,
+    },
+    Status,
+    Logs {
+        widget: String,
+        lines: usize,
+        follow: bool,
+    },
+}
+
+///
+/// A single message sent to the daemon
+///
+/// Messages are serialized as JSON and framed by a trailing newline,
+/// so arguments can contain any character, including `|`
+///
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TransferData {
+    pub version: u32,
+    #[serde(flatten)]
+    pub command: DaemonCommand,
+}
+
+impl TransferData {
+    pub fn new(command: DaemonCommand) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            command,
+        }
+    }
+}
+
+/// How a widget process finished
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ExitInfo {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl fmt::Display for ExitInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exit {}", code),
+            (None, Some(signal)) => write!(f, "signal {}", signal),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct WidgetStatus {
+    pub name: String,
+    pub running: bool,
+    pub pid: Option<u32>,
+    /// Unix timestamp in seconds of the last launch
+    pub started_at: Option<u64>,
+    /// Seconds since the last launch, only while running
+    pub uptime: Option<u64>,
+    pub restarts: u32,
+    pub last_exit: Option<ExitInfo>,
+}
+
+///
+/// The answer of the daemon to every `TransferData` received
+///
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "status", content = "message", rename_all = "kebab-case")]
+pub enum DaemonResponse {
+    Ok,
+    AlreadyOpen,
+    NotRunning,
+    NotFound,
+    SpawnError(String),
+    Error(String),
+    Status(Vec<WidgetStatus>),
+    Logs(Vec<String>),
+}
+
+impl DaemonResponse {
+    pub fn is_ok(&self) -> bool {
+        *self == DaemonResponse::Ok
+    }
+
+    /// Exit code used by the `sbbw` client, so scripts can tell failures apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DaemonResponse::Ok | DaemonResponse::Status(_) | DaemonResponse::Logs(_) => 0,
+            DaemonResponse::Error(_) => 1,
+            DaemonResponse::AlreadyOpen => 2,
+            DaemonResponse::NotRunning => 3,
+            DaemonResponse::NotFound => 4,
+            DaemonResponse::SpawnError(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for DaemonResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonResponse::Ok => write!(f, "Ok"),
+            DaemonResponse::AlreadyOpen => write!(f, "Widget already opened"),
+            DaemonResponse::NotRunning => write!(f, "Widget not running"),
+            DaemonResponse::NotFound => write!(f, "Widget not found"),
+            DaemonResponse::SpawnError(e) => write!(f, "Widget failed to start: {}", e),
+            DaemonResponse::Error(e) => write!(f, "{}", e),
+            DaemonResponse::Status(widgets) => write!(f, "{} widgets", widgets.len()),
+            DaemonResponse::Logs(lines) => write!(f, "{}", lines.join("\n")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    ///
+    /// The client sent a `command|data` message from sbbw 0.1.x, seen on a daemon running
+    /// with `--tcp`. Those clients don't read the reply, so it only shows on the daemon logs
+    ///
+    Legacy,
+    /// The client speaks another version of the protocol
+    VersionMismatch(Option<u64>),
+    /// The message could not be decoded
+    Malformed(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Legacy => write!(
+                f,
+                "Client uses the legacy `command|data` protocol, daemon expects version {}. Update the sbbw binary",
+                PROTOCOL_VERSION
+            ),
+            ProtocolError::VersionMismatch(Some(version)) => write!(
+                f,
+                "Protocol version mismatch: client sent {}, daemon expects {}",
+                version, PROTOCOL_VERSION
+            ),
+            ProtocolError::VersionMismatch(None) => write!(
+                f,
+                "Protocol version missing, daemon expects {}",
+                PROTOCOL_VERSION
+            ),
+            ProtocolError::Malformed(e) => write!(f, "Malformed message: {}", e),
+        }
+    }
+}
+
+pub fn parse_message(raw: &str) -> Result<TransferData, ProtocolError> {
+    let raw = raw.trim();
+    let value = match serde_json::from_str::<serde_json::Value>(raw) {
+        Ok(value) => value,
+        Err(_) if raw.contains('|') => return Err(ProtocolError::Legacy),
+        Err(e) => return Err(ProtocolError::Malformed(e.to_string())),
+    };
+    match value.get("version").and_then(|v| v.as_u64()) {
+        Some(version) if version == PROTOCOL_VERSION as u64 => {}
+        version => return Err(ProtocolError::VersionMismatch(version)),
+    }
+    serde_json::from_value(value).map_err(|e| ProtocolError::Malformed(e.to_string()))
+}
+
+pub fn write_message<W: Write, T: Serialize>(stream: &mut W, message: &T) -> io::Result<()> {
+    let mut data = serde_json::to_vec(message)?;
+    data.push(b'\n');
+    stream.write_all(&data)?;
+    stream.flush()
+}
+
+fn read_response<R: BufRead>(reader: &mut R) -> DaemonResponse {
+    next_response(reader).unwrap_or_else(|| {
+        DaemonResponse::Error(
+            "The daemon closed the connection without response, it may be outdated".to_string(),
+        )
+    })
+}
+
+/// Next response sent by the daemon, `None` once it closes the connection
+fn next_response<R: BufRead>(reader: &mut R) -> Option<DaemonResponse> {
+    let mut data = String::new();
+    match reader.read_line(&mut data) {
+        Ok(0) => None,
+        Ok(_) => {
+            Some(serde_json::from_str(&data).unwrap_or_else(|e| {
+                DaemonResponse::Error(format!("Invalid daemon response: {}", e))
+            }))
+        }
+        Err(e) => Some(DaemonResponse::Error(e.to_string())),
+    }
 }
 
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct Daemon {
     callback: Option<Rc<TransferDataFunc>>,
-    socket_addr: SocketAddr,
+    /// Called once this instance is listening as the main daemon
+    on_listen: Option<Rc<ListenFunc>>,
+    transport: Transport,
     client_connected: bool,
-    command: (String, String),
+    command: Option<DaemonCommand>,
 }
 
 ///
 /// Create a new Daemon
 /// # Arguments
-/// * `socket_addr` - The address to bind to
+/// * `transport` - The socket to bind to
 /// * `callback` - The callback to call when a request is received
 ///
 /// # Returns
@@ -38,20 +247,24 @@ pub struct Daemon {
 ///
 #[allow(dead_code)]
 impl Daemon {
-    pub fn new(ip: IpAddr, port: u16) -> Self {
+    pub fn new(transport: Transport) -> Self {
         Self {
-            socket_addr: SocketAddr::new(ip, port),
-            callback: Some(Rc::new(|_| {})),
+            transport,
+            callback: Some(Rc::new(|_| DaemonResponse::Ok)),
+            on_listen: None,
             client_connected: false,
-            command: ("".to_string(), "".to_string()),
+            command: None,
         }
     }
 
     pub fn set_callbacks(&mut self, callback: Rc<TransferDataFunc>) {
         self.callback = Some(callback);
     }
-    pub fn set_command(&mut self, command: String, args: String) {
-        self.command = (command, args);
+    pub fn set_on_listen(&mut self, on_listen: Rc<ListenFunc>) {
+        self.on_listen = Some(on_listen);
+    }
+    pub fn set_command(&mut self, command: DaemonCommand) {
+        self.command = Some(command);
     }
 
     pub fn is_connected(&self) -> bool {
@@ -61,83 +274,222 @@ impl Daemon {
         self.client_connected = connected;
     }
 
-    fn make_client(&mut self, stream: TcpStream) {
+    fn make_client(&mut self, stream: Box<dyn ControlStream>) -> Option<DaemonResponse> {
         self.set_connected(true);
-        self.send_command(Some(stream), self.command.0.clone(), self.command.1.clone());
+        self.command.clone().map(|command| {
+            debug!(target: "daemon", ?command, "Sending command to daemon");
+            self.send_command(Some(stream), command)
+        })
+    }
+
+    ///
+    /// Send a command to the main daemon without becoming one if it is not running
+    ///
+    pub fn request(&self, command: DaemonCommand) -> DaemonResponse {
+        self.send_command(self.transport.connect().ok(), command)
     }
 
-    #[allow(unused_comparisons)]
-    async fn handle_client(&self, stream: &mut TcpStream) {
+    ///
+    /// Like `request`, but keep reading responses until the daemon closes the connection
+    ///
+    pub fn request_each<F: FnMut(DaemonResponse)>(
+        &self,
+        command: DaemonCommand,
+        mut on_response: F,
+    ) {
+        let mut stream = match self.transport.connect() {
+            Ok(stream) => stream,
+            Err(_) => {
+                on_response(self.send_command(None, command));
+                return;
+            }
+        };
+        // a follower keeps its write side open,
+        // the daemon takes its end of file as the client leaving
+        let follow = matches!(command, DaemonCommand::Logs { follow: true, .. });
+        if let Err(e) = write_message(&mut stream, &TransferData::new(command)) {
+            on_response(DaemonResponse::Error(e.to_string()));
+            return;
+        }
+        if !follow {
+            stream.shutdown_stream(Shutdown::Write).ok();
+        }
+        let mut reader = BufReader::new(stream);
+        while let Some(response) = next_response(&mut reader) {
+            on_response(response);
+        }
+    }
+
+    async fn handle_client(&self, mut stream: Box<dyn ControlStream>) {
+        let mut reader = match stream.try_clone_stream() {
+            Ok(reader) => BufReader::new(reader),
+            Err(e) => {
+                error!(target: "daemon", "Cannot read from client: {}", e);
+                return;
+            }
+        };
         let mut data = String::new();
-        'receiver: while match stream.read_to_string(&mut data) {
-            Ok(size) => {
-                if size == 0 {
-                    break 'receiver;
+        loop {
+            data.clear();
+            let mut follow_logs = None;
+            let response = match reader.read_line(&mut data) {
+                Ok(0) => break,
+                Ok(_) => match parse_message(&data) {
+                    Ok(message) => {
+                        if let DaemonCommand::Logs {
+                            widget,
+                            follow: true,
+                            ..
+                        } = &message.command
+                        {
+                            follow_logs = logs::log_path(widget).ok();
+                        }
+                        (self.callback.as_ref().unwrap())(message.command)
+                    }
+                    Err(e) => {
+                        warn!(target: "daemon", "Invalid message from client: {}", e);
+                        DaemonResponse::Error(e.to_string())
+                    }
+                },
+                Err(e) => {
+                    error!(target: "daemon", "Cannot read from client: {}", e);
+                    break;
                 }
-                println!("Size of data: {}", size);
-                println!("Content of data: {}", data);
-                let data_split = data.split('|').map(String::from).collect::<Vec<String>>();
-                let data_response =
-                    TransferData::Get((data_split[0].clone(), data_split[1].clone()));
-                (self.callback.as_ref().unwrap())(data_response);
-                stream.shutdown(Shutdown::Both).unwrap();
-                drop(&stream);
-                true
+            };
+            let is_error = matches!(response, DaemonResponse::Error(_));
+            if let Err(e) = write_message(&mut stream, &response) {
+                error!(target: "daemon", "Cannot reply to client: {}", e);
+                break;
             }
-            Err(e) => {
-                println!("[{}] {}", "Error".red().bold(), e);
-                stream.shutdown(Shutdown::Both).unwrap();
-                false
+            if is_error {
+                break;
             }
-        } {}
+            if let (Some(path), DaemonResponse::Logs(_)) = (follow_logs, &response) {
+                // the connection now belongs to the follower, which closes it
+                logs::follow(path, stream);
+                return;
+            }
+        }
+        stream.shutdown_stream(Shutdown::Both).ok();
     }
 
     async fn make_server(&mut self) {
-        let addr = self.socket_addr.clone();
-        let listener = TcpListener::bind(addr).unwrap();
-        // accept connections and process them, spawning a new thread for each one
-        println!("{}", "Daemon running".blue());
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    self.handle_client(&mut stream).await
-                }
+        let listener = match self.transport.bind() {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(target: "daemon", "Cannot listen on {}: {}", self.transport, e);
+                return;
+            }
+        };
+        info!(target: "daemon", "Daemon running on {}", self.transport);
+        if let Some(on_listen) = &self.on_listen {
+            on_listen();
+        }
+        // accept connections and process them one by one
+        loop {
+            match listener.accept() {
+                Ok(stream) => self.handle_client(stream).await,
                 Err(e) => {
-                    println!("[{}] {}", "Error".red().bold(), e);
+                    error!(target: "daemon", "Cannot accept client: {}", e);
                 }
             }
         }
-        // close the socket server
-        drop(listener);
     }
 
-    pub async fn run(&mut self) {
-        let addr = self.socket_addr.clone();
-        match TcpStream::connect(&addr) {
+    ///
+    /// Returns the response of the main daemon when this instance acted as a client
+    ///
+    pub async fn run(&mut self) -> Option<DaemonResponse> {
+        match self.transport.connect() {
             Ok(stream) => {
-                println!("{}", "Successfully connected to daemon".green().bold());
-                self.make_client(stream);
+                debug!(target: "daemon", "Connected to daemon on {}", self.transport);
+                self.make_client(stream)
             }
             Err(_e) => {
                 // create new conection
-                println!("{}", "Creating new daemon".blue().bold());
+                info!(target: "daemon", "Creating new daemon");
                 self.make_server().await;
+                None
             }
         }
     }
 
-    pub fn send_command(&self, client_stream: Option<TcpStream>, command: String, data: String) {
-        match client_stream.as_ref() {
+    pub fn send_command(
+        &self,
+        client_stream: Option<Box<dyn ControlStream>>,
+        command: DaemonCommand,
+    ) -> DaemonResponse {
+        match client_stream {
             Some(mut stream) => {
-                println!("{}: \"{}\" \"{}\"", "Sending command to daemon".blue().bold(), command, data);
-                let data_response = format!("{}|{}", command, data);
-                stream.write(data_response.as_bytes()).unwrap();
-                stream.flush().unwrap();
-                stream.shutdown(Shutdown::Both).unwrap();
-            }
-            None => {
-                println!("{}", "The main daemon is not running".green().bold());
+                if let Err(e) = write_message(&mut stream, &TransferData::new(command)) {
+                    return DaemonResponse::Error(e.to_string());
+                }
+                stream.shutdown_stream(Shutdown::Write).ok();
+                read_response(&mut BufReader::new(stream))
             }
+            None => DaemonResponse::Error("The main daemon is not running".to_string()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_roundtrip_keeps_pipes() {
+        let message = TransferData::new(DaemonCommand::Test {
+            widget: "bar".to_string(),
+            url: "http://localhost:3000/?a=1|2".to_string(),
+        });
+        let mut buf = Vec::new();
+        write_message(&mut buf, &message).unwrap();
+
+        assert_eq!(buf.last(), Some(&b'\n'));
+        assert_eq!(
+            parse_message(std::str::from_utf8(&buf).unwrap()),
+            Ok(message)
+        );
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        for response in [
+            DaemonResponse::Ok,
+            DaemonResponse::AlreadyOpen,
+            DaemonResponse::SpawnError("No such file or directory".to_string()),
+        ] {
+            let mut buf = Vec::new();
+            write_message(&mut buf, &response).unwrap();
+            assert_eq!(read_response(&mut buf.as_slice()), response);
+        }
+        assert!(matches!(
+            read_response(&mut "".as_bytes()),
+            DaemonResponse::Error(_)
+        ));
+    }
+
+    #[test]
+    fn legacy_message_is_rejected() {
+        assert_eq!(parse_message("open|sidebar"), Err(ProtocolError::Legacy));
+        assert!(matches!(
+            parse_message("open"),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn version_mismatch_is_rejected() {
+        let raw = r#"{"version":99,"command":"open","args":{"widget":"bar"}}"#;
+        assert_eq!(
+            parse_message(raw),
+            Err(ProtocolError::VersionMismatch(Some(99)))
+        );
+
+        let raw = r#"{"command":"open","args":{"widget":"bar"}}"#;
+        assert_eq!(
+            parse_message(raw),
+            Err(ProtocolError::VersionMismatch(None))
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use sbbw_widget_conf::LOG_ENV;
+use std::io::{self, IsTerminal};
+use tracing_subscriber::EnvFilter;
+
+///
+/// Setup the logger of the daemon and the cli, writing to stderr
+///
+/// `verbosity` is the number of `-v` minus the number of `-q`, when it is `0`
+/// the filter is read from `SBBW_LOG` and defaults to `info`, otherwise the level
+/// is exported in `SBBW_LOG` so the widgets launched later use it too.
+/// Colors are only used when stderr is a terminal
+///
+pub fn init(verbosity: i8) {
+    let level = match verbosity {
+        0 => None,
+        i8::MIN..=-2 => Some("error"),
+        -1 => Some("warn"),
+        1 => Some("debug"),
+        _ => Some("trace"),
+    };
+    let filter = match level {
+        Some(level) => {
+            std::env::set_var(LOG_ENV, level);
+            EnvFilter::new(level)
+        }
+        None => EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+}
@@ -0,0 +1,226 @@
+use crate::{
+    daemon::{write_message, DaemonResponse},
+    transport::ControlStream,
+};
+use chrono::Local;
+use sbbw_widget_conf::get_logs_path;
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tracing::error;
+
+/// Size of a log file before it is rotated
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+/// Rotated files kept for each widget, as `<widget>.log.1`, `<widget>.log.2`...
+const MAX_ROTATED_LOGS: usize = 2;
+/// How often a followed log file is checked for new lines
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+pub fn log_path(widget: &str) -> io::Result<PathBuf> {
+    Ok(get_logs_path()?.join(format!("{}.log", widget)))
+}
+
+///
+/// Log file of a widget, rotated when it grows over `MAX_LOG_SIZE`
+///
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    /// Write a line prefixed with the current time and the stream it comes from
+    pub fn write_line(&mut self, stream: &str, line: &str) -> io::Result<()> {
+        let line = format!(
+            "{} [{}] {}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            stream,
+            line.trim_end()
+        );
+        if self.size > 0 && self.size + line.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..MAX_ROTATED_LOGS).rev() {
+            let from = rotated_path(&self.path, i);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        *self = LogFile::open(self.path.clone())?;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    PathBuf::from(path)
+}
+
+///
+/// Copy each line read from a widget output into its log file, until the pipe is closed
+///
+pub fn capture<R: Read + Send + 'static>(
+    reader: R,
+    stream: &'static str,
+    log: Arc<Mutex<LogFile>>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let text = String::from_utf8_lossy(&line);
+                    if let Err(e) = log.lock().unwrap().write_line(stream, &text) {
+                        error!(target: "daemon", "Cannot write widget log: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Last `count` lines of a log file
+pub fn tail(path: &Path, count: usize) -> io::Result<Vec<String>> {
+    let mut lines = VecDeque::with_capacity(count);
+    for line in BufReader::new(File::open(path)?).lines() {
+        if lines.len() == count {
+            lines.pop_front();
+        }
+        lines.push_back(line?);
+    }
+    Ok(lines.into())
+}
+
+///
+/// Send the lines appended to a log file to a client until it disconnects,
+/// reopening the file when it is rotated
+///
+pub fn follow(path: PathBuf, mut stream: Box<dyn ControlStream>) {
+    thread::spawn(move || {
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        let mut position = file.seek(SeekFrom::End(0)).unwrap_or(0);
+        let mut pending = String::new();
+        loop {
+            thread::sleep(FOLLOW_INTERVAL);
+            if client_left(&mut stream) {
+                break;
+            }
+            let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if len < position {
+                // rotated, continue from the start of the new file
+                match File::open(&path) {
+                    Ok(new_file) => file = new_file,
+                    Err(_) => continue,
+                }
+                position = 0;
+            }
+            let mut chunk = String::new();
+            if file.seek(SeekFrom::Start(position)).is_err() {
+                continue;
+            }
+            let read = match file.read_to_string(&mut chunk) {
+                Ok(read) => read,
+                Err(_) => continue,
+            };
+            position += read as u64;
+            pending.push_str(&chunk);
+            if let Some(end) = pending.rfind('\n') {
+                let lines = pending[..end].lines().map(String::from).collect();
+                pending.drain(..=end);
+                if write_message(&mut stream, &DaemonResponse::Logs(lines)).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+///
+/// Whether a following client closed its connection, checked without blocking.
+/// It sends nothing after its request, so anything read is dropped
+///
+fn client_left(stream: &mut Box<dyn ControlStream>) -> bool {
+    if stream.set_nonblocking_stream(true).is_err() {
+        return true;
+    }
+    let mut buf = [0u8; 64];
+    let left = match stream.read(&mut buf) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ),
+    };
+    stream.set_nonblocking_stream(false).is_err() || left
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_file_rotates() {
+        let dir = std::env::temp_dir().join(format!("sbbw-test-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bar.log");
+
+        let mut log = LogFile::open(path.clone()).unwrap();
+        let line = "x".repeat(1024);
+        for _ in 0..(3 * MAX_LOG_SIZE / 1024) {
+            log.write_line("stdout", &line).unwrap();
+        }
+
+        assert!(fs::metadata(&path).unwrap().len() <= MAX_LOG_SIZE);
+        assert!(rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+
+        let last = tail(&path, 2).unwrap();
+        assert_eq!(last.len(), 2);
+        assert!(last[1].contains("[stdout] xxx"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follower_notices_the_client_leaving() {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut server: Box<dyn ControlStream> = Box::new(server);
+        assert!(!client_left(&mut server));
+
+        // the stream is back to blocking mode for the writes
+        write_message(&mut server, &DaemonResponse::Logs(vec![])).unwrap();
+        drop(client);
+        assert!(client_left(&mut server));
+    }
+}
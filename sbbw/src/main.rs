@@ -1,25 +1,35 @@
 #![feature(proc_macro_hygiene, decl_macro, str_split_as_str)]
 use clap::{App, Arg};
 use colored::*;
-use daemon::{Daemon, TransferData};
+use daemon::{Daemon, DaemonCommand, DaemonResponse, WidgetStatus};
 use rocket::response::{content, status::NotFound, NamedFile};
-use sbbw_widget_conf::{get_config_path, get_widgets, get_widgets_path, validate_config_toml};
 use sbbw_exec::autostarts;
+use sbbw_widget_conf::{
+    config_dir_override, get_config_path, get_widgets, get_widgets_path,
+    is_valid_class_name, split_instance, validate_config_toml, CONFIG_DIR_ENV,
+};
 use std::{
-    collections::HashMap,
     env,
-    fs::{File, OpenOptions},
-    net::{IpAddr, TcpStream},
+    io::{self, IsTerminal},
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
-    process::{Command, Stdio},
+    process::Command,
     rc::Rc,
     sync::{Arc, Mutex},
 };
+use tracing::{debug, error};
+use transport::Transport;
+use widgets::{Widgets, REAP_INTERVAL};
 
 #[macro_use]
 extern crate rocket;
 
 mod daemon;
+mod logging;
+mod logs;
+mod transport;
+mod watch;
+mod widgets;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
@@ -27,8 +37,9 @@ const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 
 #[get("/<file..>")]
 fn load_widget(file: PathBuf) -> Result<NamedFile, NotFound<String>> {
-    println!("{} {}", "Loading:".green().bold(), file.to_str().unwrap());
-    let path = get_widgets_path().join(&file);
+    debug!(target: "http", file = %file.display(), "Loading");
+    let widgets_path = get_widgets_path().map_err(|e| NotFound(e.to_string()))?;
+    let path = widgets_path.join(&file);
     if path.is_file() {
         NamedFile::open(&path).map_err(|e| NotFound(e.to_string()))
     } else if path.is_dir() {
@@ -37,12 +48,8 @@ fn load_widget(file: PathBuf) -> Result<NamedFile, NotFound<String>> {
         let mut path_arr = file.to_str().unwrap().split("/");
         let widget_name = path_arr.next().unwrap();
         let file = PathBuf::from(path_arr.as_str());
-        let path = get_widgets_path().join(widget_name).join("ui").join(&file);
-        println!(
-            "{} {}",
-            "Path converted:".green().bold(),
-            &path.to_str().unwrap()
-        );
+        let path = widgets_path.join(widget_name).join("ui").join(&file);
+        debug!(target: "http", path = %path.display(), "Path converted");
         NamedFile::open(&path).map_err(|e| NotFound(e.to_string()))
     }
 }
@@ -92,26 +99,53 @@ fn default_catcher() -> content::Html<&'static str> {
 
 #[tokio::main]
 async fn main() {
+    if let Some(dir) = config_dir_arg() {
+        env::set_var(CONFIG_DIR_ENV, dir);
+    }
     // convert themes into &[&str]
-    let widgets = get_widgets();
+    let widgets = get_widgets().unwrap_or_default();
     let widgets: Vec<&str> = widgets.iter().map(|s| s.as_str()).collect();
     let matches = App::new("Sbbw Daemon")
         .about(DESCRIPTION)
         .version(VERSION)
         .author(AUTHORS)
         .args(&[
-            // Arg::new("ip")
-            //     .short('i')
-            //     .long("ip")
-            //     .value_name("IP")
-            //     .help("IP address to listen on")
-            //     .takes_value(true)
-            //     .default_value("0.0.0.0"),
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Show more logs, can be repeated (-vv)")
+                .global(true)
+                .multiple_occurrences(true)
+                .takes_value(false),
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .help("Show less logs, can be repeated (-qq)")
+                .global(true)
+                .multiple_occurrences(true)
+                .takes_value(false),
+            Arg::new("config-dir")
+                .long("config-dir")
+                .value_name("DIR")
+                .help("Root of the config instead of ~/.config/sbbw, also set by SBBW_CONFIG_DIR")
+                .global(true)
+                .takes_value(true),
+            Arg::new("tcp")
+                .long("tcp")
+                .help("Use a TCP socket for the daemon instead of the per-user unix socket")
+                .takes_value(false),
+            Arg::new("ip")
+                .short('i')
+                .long("ip")
+                .value_name("IP")
+                .help("IP address to listen on with --tcp")
+                .takes_value(true)
+                .default_value("127.0.0.1"),
             Arg::new("port")
                 .short('p')
                 .long("port")
                 .value_name("PORT")
-                .help("Port to listen on")
+                .help("Port to listen on with --tcp")
                 .takes_value(true)
                 .default_value("8111"),
             Arg::new("open")
@@ -124,17 +158,15 @@ async fn main() {
             Arg::new("close")
                 .short('c')
                 .long("close")
-                .help("Close the widget")
+                .help("Close the widget, or one of its instances like `bar@DP-1`")
                 .takes_value(true)
-                .conflicts_with_all(&["open", "test"])
-                .possible_values(&widgets),
+                .conflicts_with_all(&["open", "test"]),
             Arg::new("toggle")
                 .short('t')
                 .long("toggle")
-                .help("Toggle view the widget")
+                .help("Toggle view the widget, or one of its instances like `bar@DP-1`")
                 .conflicts_with_all(&["open", "close", "test"])
-                .takes_value(true)
-                .possible_values(&widgets),
+                .takes_value(true),
             Arg::new("test")
                 .long("test")
                 .help("Test the widget")
@@ -154,8 +186,114 @@ async fn main() {
                 .help("Show all widgets installeds")
                 .takes_value(false),
         ])
+        .subcommand(
+            App::new("status")
+                .about("Show the state of the widgets managed by the daemon")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print the status as JSON")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            App::new("logs")
+                .about("Show the output of a widget")
+                .args(&[
+                    Arg::new("widget").required(true).help("Widget to show"),
+                    Arg::new("follow")
+                        .short('f')
+                        .long("follow")
+                        .help("Keep printing new lines")
+                        .takes_value(false),
+                    Arg::new("lines")
+                        .short('n')
+                        .long("lines")
+                        .value_name("LINES")
+                        .help("Number of lines to show")
+                        .takes_value(true)
+                        .default_value("50"),
+                ]),
+        )
         .get_matches();
 
+    let verbosity =
+        matches.occurrences_of("verbose") as i64 - matches.occurrences_of("quiet") as i64;
+    logging::init(verbosity.clamp(-2, 2) as i8);
+    if !io::stdout().is_terminal() {
+        colored::control::set_override(false);
+    }
+
+    let widgets_path = match get_widgets_path() {
+        Ok(path) => path,
+        Err(e) => {
+            error!(target: "daemon", "Cannot use the config directory: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // widgets run on another working directory, hand them the config root as an absolute path
+    if config_dir_override().is_some() {
+        if let Ok(root) = get_config_path() {
+            env::set_var(CONFIG_DIR_ENV, root);
+        }
+    }
+
+    let ip = matches.value_of("ip").unwrap().parse::<IpAddr>().unwrap();
+    let port: u16 = matches.value_of("port").unwrap().parse::<u16>().unwrap();
+    let addr = SocketAddr::new(ip, port);
+
+    let transport = if matches.is_present("tcp") {
+        Transport::Tcp(addr)
+    } else {
+        match Transport::local(addr) {
+            Ok(transport) => transport,
+            Err(e) => {
+                error!(target: "daemon", "{}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    if let Some(status_matches) = matches.subcommand_matches("status") {
+        match Daemon::new(transport).request(DaemonCommand::Status) {
+            DaemonResponse::Status(widgets) => {
+                if status_matches.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&widgets).unwrap());
+                } else {
+                    print_status(&widgets);
+                }
+            }
+            response => {
+                eprintln!("[{}] {}", for_stderr("Error".red().bold()), response);
+                std::process::exit(response.exit_code());
+            }
+        }
+        return;
+    }
+
+    if let Some(logs_matches) = matches.subcommand_matches("logs") {
+        let command = DaemonCommand::Logs {
+            widget: logs_matches.value_of("widget").unwrap().to_string(),
+            lines: logs_matches
+                .value_of_t("lines")
+                .unwrap_or_else(|e| e.exit()),
+            follow: logs_matches.is_present("follow"),
+        };
+        let mut exit_code = 0;
+        Daemon::new(transport).request_each(command, |response| match response {
+            DaemonResponse::Logs(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            response => {
+                eprintln!("[{}] {}", for_stderr("Error".red().bold()), response);
+                exit_code = response.exit_code();
+            }
+        });
+        std::process::exit(exit_code);
+    }
+
     if matches.is_present("show-windows") {
         println!("{}", "Installed widgets:".blue().bold());
         for widget in widgets {
@@ -164,221 +302,145 @@ async fn main() {
         return;
     }
 
-    let mut command = String::new();
-    let mut value_command = String::new();
-
-    println!("{}", "Sbbw Daemon".green());
+    let mut command = None;
 
     if let Some(value) = matches.value_of("open") {
         if widgets.contains(&value) {
-            command.push_str("open");
-            value_command.push_str(value);
+            command = Some(DaemonCommand::Open {
+                widget: value.to_string(),
+            });
         } else {
-            println!(
-                "{}",
-                "Widget {} not found"
-                    .red()
-                    .replace("{}", &value.yellow().bold())
-            );
-            return;
+            error!(widget = %value, "Widget not found");
+            std::process::exit(DaemonResponse::NotFound.exit_code());
         }
     }
 
     if let Some(value) = matches.value_of("close") {
-        if widgets.contains(&value) {
-            command.push_str("close");
-            value_command.push_str(value);
+        if widgets.contains(&split_instance(value).0) {
+            command = Some(DaemonCommand::Close {
+                widget: value.to_string(),
+            });
         } else {
-            println!(
-                "{}",
-                "Widget {} not found"
-                    .red()
-                    .replace("{}", &value.yellow().bold())
-            );
-            return;
+            error!(widget = %value, "Widget not found");
+            std::process::exit(DaemonResponse::NotFound.exit_code());
         }
     }
 
     if let Some(value) = matches.value_of("toggle") {
-        if widgets.contains(&value) {
-            command.push_str("toggle");
-            value_command.push_str(value);
+        if widgets.contains(&split_instance(value).0) {
+            command = Some(DaemonCommand::Toggle {
+                widget: value.to_string(),
+            });
         } else {
-            println!(
-                "{}",
-                "Widget {} not found"
-                    .red()
-                    .replace("{}", &value.yellow().bold())
-            );
-            return;
+            error!(widget = %value, "Widget not found");
+            std::process::exit(DaemonResponse::NotFound.exit_code());
         }
     }
 
     if let Some(value) = matches.values_of("test") {
         let values: Vec<&str> = value.collect();
         if values.len() == 2 {
-            command.push_str("test");
-            value_command.push_str(values.join(" ").as_str())
+            command = Some(DaemonCommand::Test {
+                widget: values[0].to_string(),
+                url: values[1].to_string(),
+            });
         } else {
-            println!("{}", "Widget not found".red());
-            return;
+            error!("Widget not found");
+            std::process::exit(DaemonResponse::NotFound.exit_code());
         }
-        println!("Command: {}, Values: {}", command, value_command);
     }
 
     if let Some(value) = matches.value_of("check-config") {
         if widgets.contains(&value) {
-            let path_conf = get_widgets_path().join(value).join("config.toml");
+            let path_conf = widgets_path.join(value).join("config.toml");
             if path_conf.exists() {
-                if validate_config_toml(path_conf).is_err() {
-                    println!(
-                        "{}",
-                        "Config of widget {} is not valid"
-                            .red()
-                            .replace("{}", &value.yellow().bold())
-                    );
-                    return;
-                } else {
-                    println!(
-                        "{}",
-                        "Config of widget {} is valid"
-                            .green()
-                            .replace("{}", &value.yellow().bold())
-                    );
-                    return;
+                match validate_config_toml(path_conf) {
+                    Ok(conf) => {
+                        if !is_valid_class_name(&conf.class_name) {
+                            eprintln!(
+                                "{} class_name `{}` should only have letters, digits, `_`, `-` and `.` to be matched by window rules",
+                                for_stderr("warning:".yellow().bold()),
+                                conf.class_name
+                            );
+                        }
+                        println!(
+                            "{}",
+                            "Config of widget {} is valid"
+                                .green()
+                                .replace("{}", &value.yellow().bold())
+                        );
+                        return;
+                    }
+                    Err(e) => {
+                        let diagnostic = e.diagnostic();
+                        let diagnostic = diagnostic.strip_prefix("error:").unwrap_or(&diagnostic);
+                        eprint!("{}{}", for_stderr("error:".red().bold()), diagnostic);
+                        std::process::exit(1);
+                    }
                 }
             }
         }
-        println!(
-            "{}",
-            "Widget {} not found"
-                .red()
-                .replace("{}", &value.yellow().bold())
+        eprintln!(
+            "{} {} {}",
+            for_stderr("Widget".red()),
+            for_stderr(value.yellow().bold()),
+            for_stderr("not found".red())
         );
-        return;
+        std::process::exit(DaemonResponse::NotFound.exit_code());
     }
 
-    match Command::new("sbbw-widget").spawn() {
-        Ok(_) => println!("{}", "Binary for launch Widgets alredy exists".green()),
+    // without arguments sbbw-widget exits right away, wait it so it doesn't stay as zombie
+    match Command::new("sbbw-widget")
+        .spawn()
+        .and_then(|mut child| child.wait())
+    {
+        Ok(_) => debug!(target: "daemon", "Binary for launch Widgets already exists"),
         Err(e) => {
-            println!(
-                "{} reason: {:?}",
-                "Binary for launch Widgets not found".red().bold(),
-                e
-            );
+            error!(target: "daemon", "Binary for launch Widgets not found: {}", e);
             return;
         }
     }
 
     autostarts();
 
-    let ip = "0.0.0.0".parse::<IpAddr>().unwrap();
-    let port: u16 = matches.value_of("port").unwrap().parse::<u16>().unwrap();
-
-    let mut daemon = Daemon::new(ip, port);
-    if command.len() > 0 && value_command.len() > 0 {
-        daemon.set_command(command, value_command);
+    let mut daemon = Daemon::new(transport.clone());
+    if let Some(command) = command {
+        daemon.set_command(command);
     }
 
-    // create hashmap for save all subprocess excecuted with widget-name as key
-    let subprocesses = Arc::new(Mutex::new(HashMap::new()));
+    // all subprocess excecuted with widget-name as key
+    let widgets = Arc::new(Mutex::new(Widgets::default()));
 
-    let receiver_data_callback = Rc::new(move |response: TransferData| match response {
-        TransferData::Get((command, data)) => match command.as_str() {
-            "open" => {
-                if subprocesses.lock().unwrap().contains_key(&data) {
-                    println!(
-                        "{}",
-                        "Widget {} already opened"
-                            .red()
-                            .replace("{}", &data.yellow().bold())
-                    );
-                    return;
-                }
-                println!("Open: {:?}", data);
-                let file = OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(get_config_path().join(".log"))
-                    .unwrap();
-                let out = Stdio::from(file);
-                let subprocess = Command::new("sbbw-widget")
-                    .arg(data.as_str())
-                    .stderr(out)
-                    .spawn()
-                    .unwrap();
-                subprocesses.lock().unwrap().insert(data, subprocess);
-            }
-            "close" => {
-                if !subprocesses.lock().unwrap().contains_key(&data) {
-                    println!(
-                        "{}",
-                        "Widget {} not running"
-                            .red()
-                            .replace("{}", &data.yellow().bold())
-                    );
-                    return;
-                }
-                println!("Close: {:?}", data);
-                if let Some(mut subprocess) = subprocesses.lock().unwrap().remove(&data) {
-                    subprocess.kill().unwrap();
-                }
-            }
-            "toggle" => {
-                if !subprocesses.lock().unwrap().contains_key(&data) {
-                    println!("Open: {:?}", data);
-                    let file = OpenOptions::new()
-                        .append(true)
-                        .create(true)
-                        .open(get_config_path().join(".log"))
-                        .unwrap();
-                    let out = Stdio::from(file);
-                    let subprocess = Command::new("sbbw-widget")
-                        .arg(data.as_str())
-                        .stderr(out)
-                        .spawn()
-                        .unwrap();
-                    subprocesses.lock().unwrap().insert(data, subprocess);
-                } else {
-                    println!("Close: {:?}", data);
-                    if let Some(mut subprocess) = subprocesses.lock().unwrap().remove(&data) {
-                        subprocess.kill().unwrap();
-                    }
-                }
-            }
-            "test" => {
-                if subprocesses.lock().unwrap().contains_key(&data) {
-                    println!(
-                        "{}",
-                        "Widget {} already opened"
-                            .red()
-                            .replace("{}", &data.yellow().bold())
-                    );
-                    return;
-                }
-                println!("Open to Test: {:?}", data);
-                let f = File::create(get_config_path().join(".log")).unwrap();
-                let out = Stdio::from(f);
-                let subprocess = Command::new("sbbw-widget")
-                    .args(data.split(" "))
-                    .stderr(out)
-                    .spawn()
-                    .unwrap();
-                println!("Args to test: {:?}", data);
-                subprocesses.lock().unwrap().insert(data.split(" ").next().unwrap().to_string(), subprocess);
-            }
-            _ => {
-                println!("{}", "Unknown command".red().bold());
-            }
-        },
-        _ => {}
+    // only the process that becomes the daemon, once it is listening, watches the widgets
+    let daemon_widgets = widgets.clone();
+    daemon.set_on_listen(Rc::new(move || {
+        // keep the state in sync when a widget crashes or its window is closed,
+        // and restart it if its config asks for it
+        let supervisor = daemon_widgets.clone();
+        std::thread::spawn(move || loop {
+            supervisor.lock().unwrap().supervise();
+            std::thread::sleep(REAP_INTERVAL);
+        });
+        watch::spawn(daemon_widgets.clone());
+    }));
+
+    let receiver_data_callback = Rc::new(move |command: DaemonCommand| match command {
+        DaemonCommand::Open { widget } => widgets.lock().unwrap().open(widget),
+        // closing waits the widgets to exit, without keeping them locked meanwhile
+        DaemonCommand::Close { widget } => widgets::close(&widgets, &widget),
+        DaemonCommand::Toggle { widget } => widgets::toggle(&widgets, widget),
+        DaemonCommand::Test { widget, url } => widgets.lock().unwrap().test(widget, url),
+        DaemonCommand::Status => DaemonResponse::Status(widgets.lock().unwrap().status()),
+        DaemonCommand::Logs { widget, lines, .. } => {
+            widgets.lock().unwrap().logs(&widget, lines)
+        }
     });
     daemon.set_callbacks(receiver_data_callback);
 
     tokio::spawn(async move {
         // let mut config = rocket::Config::new(rocket::config::Environment::Production);
         // config.set_log_level(rocket::config::LoggingLevel::Debug);
-        match TcpStream::connect(("0.0.0.0", port)) {
+        match transport.connect() {
             Ok(_) => {}
             Err(_) => {
                 let err = rocket::ignite()
@@ -389,5 +451,75 @@ async fn main() {
             }
         }
     });
-    tokio::join!(async move { daemon.run().await });
+    let (response,) = tokio::join!(async move { daemon.run().await });
+    if let Some(response) = response {
+        if response.is_ok() {
+            println!("{}", response.to_string().green().bold());
+        } else {
+            eprintln!("[{}] {}", for_stderr("Error".red().bold()), response);
+        }
+        std::process::exit(response.exit_code());
+    }
+}
+
+///
+/// `--config-dir` has to be known before parsing the arguments,
+/// since the installed widgets are the possible values of some of them
+///
+fn config_dir_arg() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config-dir" {
+            return args.next();
+        }
+        if let Some(dir) = arg.strip_prefix("--config-dir=") {
+            return Some(dir.to_string());
+        }
+    }
+    None
+}
+
+///
+/// Colors are only turned off when stdout is not a terminal,
+/// the messages printed to stderr have to check it on their own
+///
+fn for_stderr(text: ColoredString) -> ColoredString {
+    if io::stderr().is_terminal() {
+        text
+    } else {
+        text.clear()
+    }
+}
+
+fn print_status(widgets: &[WidgetStatus]) {
+    println!(
+        "{}",
+        format!(
+            "{:<20} {:<8} {:>8} {:>10} {:>9}  {}",
+            "WIDGET", "STATE", "PID", "UPTIME", "RESTARTS", "LAST EXIT"
+        )
+        .bold()
+    );
+    for widget in widgets {
+        let state = if widget.running {
+            format!("{:<8}", "running").green()
+        } else {
+            format!("{:<8}", "stopped").red()
+        };
+        let uptime = widget.uptime.map_or("-".to_string(), |secs| {
+            format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+        });
+        println!(
+            "{:<20} {} {:>8} {:>10} {:>9}  {}",
+            widget.name,
+            state,
+            widget.pid.map_or("-".to_string(), |pid| pid.to_string()),
+            uptime,
+            widget.restarts,
+            widget
+                .last_exit
+                .as_ref()
+                .map_or("-".to_string(), |exit| exit.to_string())
+        );
+    }
 }
@@ -0,0 +1,259 @@
+use std::{
+    fmt, io,
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+};
+
+#[cfg(unix)]
+use sbbw_widget_conf::{config_dir_override, get_config_path};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+///
+/// Where the daemon control channel listens
+///
+/// On unix the default is a per-user socket under `$XDG_RUNTIME_DIR/sbbw/`,
+/// TCP is only used when asked for or when unix sockets are not available
+///
+#[derive(Clone, Debug)]
+pub enum Transport {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp(addr) => write!(f, "tcp://{}", addr),
+            #[cfg(unix)]
+            Transport::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+pub trait ControlStream: Read + Write + Send {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn ControlStream>>;
+    fn shutdown_stream(&self, how: Shutdown) -> io::Result<()>;
+    fn set_nonblocking_stream(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl ControlStream for TcpStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn ControlStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+    fn shutdown_stream(&self, how: Shutdown) -> io::Result<()> {
+        self.shutdown(how)
+    }
+    fn set_nonblocking_stream(&self, nonblocking: bool) -> io::Result<()> {
+        self.set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl ControlStream for UnixStream {
+    fn try_clone_stream(&self) -> io::Result<Box<dyn ControlStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+    fn shutdown_stream(&self, how: Shutdown) -> io::Result<()> {
+        self.shutdown(how)
+    }
+    fn set_nonblocking_stream(&self, nonblocking: bool) -> io::Result<()> {
+        self.set_nonblocking(nonblocking)
+    }
+}
+
+pub enum ControlListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl ControlListener {
+    pub fn accept(&self) -> io::Result<Box<dyn ControlStream>> {
+        match self {
+            ControlListener::Tcp(listener) => Ok(Box::new(listener.accept()?.0)),
+            #[cfg(unix)]
+            ControlListener::Unix(listener, _) => Ok(Box::new(listener.accept()?.0)),
+        }
+    }
+}
+
+impl Drop for ControlListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let ControlListener::Unix(_, path) = self {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+impl Transport {
+    ///
+    /// The default transport of the platform, falling back to TCP on `fallback`
+    /// where unix sockets are not supported
+    ///
+    #[allow(unused_variables)]
+    pub fn local(fallback: SocketAddr) -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            Ok(Transport::Unix(socket_dir()?.join(socket_name()?)))
+        }
+        #[cfg(not(unix))]
+        {
+            Ok(Transport::Tcp(fallback))
+        }
+    }
+
+    pub fn connect(&self) -> io::Result<Box<dyn ControlStream>> {
+        match self {
+            Transport::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            Transport::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        }
+    }
+
+    pub fn bind(&self) -> io::Result<ControlListener> {
+        match self {
+            Transport::Tcp(addr) => Ok(ControlListener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Transport::Unix(path) => {
+                // nobody answered on connect, so a leftover socket file is stale
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+                Ok(ControlListener::Unix(listener, path.clone()))
+            }
+        }
+    }
+}
+
+///
+/// Directory holding the daemon socket, created with `0700` permissions.
+/// Fails if it already exists and is owned by another user or is accessible by others
+///
+#[cfg(unix)]
+fn socket_dir() -> io::Result<PathBuf> {
+    let uid = unsafe { libc::getuid() };
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) if !runtime_dir.is_empty() => PathBuf::from(runtime_dir).join("sbbw"),
+        _ => std::env::temp_dir().join(format!("sbbw-{}", uid)),
+    };
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+    }
+    check_permissions(&dir, uid)?;
+    Ok(dir)
+}
+
+///
+/// Name of the socket, each relocated config root gets its own one
+/// so isolated setups run their own daemon
+///
+#[cfg(unix)]
+fn socket_name() -> io::Result<String> {
+    if config_dir_override().is_none() {
+        return Ok("sbbw.sock".to_string());
+    }
+    let root = get_config_path()?;
+    Ok(format!(
+        "sbbw-{:016x}.sock",
+        fnv1a(root.as_os_str().as_bytes())
+    ))
+}
+
+///
+/// 64-bit FNV-1a hash, the socket name must not change between builds
+/// or a newer cli would not find a running daemon
+///
+#[cfg(unix)]
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(unix)]
+fn check_permissions(dir: &Path, uid: u32) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+    if metadata.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is owned by another user", dir.display()),
+        ));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is accessible by other users (mode {:o}), expected 700",
+                dir.display(),
+                metadata.mode() & 0o777
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_dir_must_be_private() {
+        let uid = unsafe { libc::getuid() };
+        let dir = std::env::temp_dir().join(format!("sbbw-test-perms-{}", std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        assert!(check_permissions(&dir, uid).is_ok());
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let err = check_permissions(&dir, uid).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn socket_names_are_stable() {
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"/home/user/.config/sbbw"), 0x10a1_2dc3_3956_8df9);
+    }
+
+    #[test]
+    fn unix_transport_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("sbbw-test-sock-{}", std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        let transport = Transport::Unix(dir.join("sbbw.sock"));
+
+        let listener = transport.bind().unwrap();
+        let mut client = transport.connect().unwrap();
+        let mut server = listener.accept().unwrap();
+        client.write_all(b"ping\n").unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping\n");
+
+        drop(listener);
+        assert!(!dir.join("sbbw.sock").exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
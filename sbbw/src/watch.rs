@@ -0,0 +1,146 @@
+use crate::widgets::{self, Widgets};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use sbbw_widget_conf::get_widgets_path;
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tracing::{debug, error, warn};
+
+///
+/// Time without new changes before the widgets are reloaded,
+/// editors and bundlers usually write several files in a row
+///
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// What changed on the folder of a widget
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    /// A file of `ui`, the page is reloaded
+    Ui,
+    /// `config.toml`, the widget is restarted if the new config is valid
+    Config,
+}
+
+///
+/// Widget and kind of change of a path inside the widgets folder,
+/// `None` for files sbbw does not reload, like the ones on `scripts`
+///
+pub fn classify(widgets_path: &Path, path: &Path) -> Option<(String, Change)> {
+    let mut components = path.strip_prefix(widgets_path).ok()?.components();
+    let widget = components.next()?.as_os_str().to_str()?.to_string();
+    match components.next()?.as_os_str().to_str()? {
+        "config.toml" => Some((widget, Change::Config)),
+        "ui" => Some((widget, Change::Ui)),
+        _ => None,
+    }
+}
+
+///
+/// Watch the folders of the open widgets and reload them when their files change
+///
+pub fn spawn(widgets: Arc<Mutex<Widgets>>) {
+    let widgets_path = match get_widgets_path() {
+        Ok(path) => path,
+        Err(e) => {
+            error!(target: "watch", "Cannot watch the widgets: {}", e);
+            return;
+        }
+    };
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!(target: "watch", "Cannot watch the widgets: {}", e);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        let mut watched = HashSet::new();
+        let mut pending = BTreeSet::new();
+        loop {
+            match receiver.recv_timeout(DEBOUNCE) {
+                Ok(Ok(event)) => {
+                    pending.extend(
+                        event
+                            .paths
+                            .iter()
+                            .filter_map(|path| classify(&widgets_path, path)),
+                    );
+                    continue;
+                }
+                Ok(Err(e)) => warn!(target: "watch", "Error watching the widgets: {}", e),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+
+            for (widget, change) in std::mem::take(&mut pending) {
+                match change {
+                    Change::Ui => widgets.lock().unwrap().reload_ui(&widget),
+                    Change::Config => widgets::reload_config(&widgets, &widget),
+                }
+            }
+            let open = widgets.lock().unwrap().open_widgets();
+            update_watches(&mut watcher, &widgets_path, &mut watched, open);
+        }
+    });
+}
+
+///
+/// Watch the widgets opened since the last call and stop watching the closed ones.
+/// The folder itself is watched instead of `config.toml`, so files replaced
+/// by editors on save are still seen
+///
+fn update_watches(
+    watcher: &mut RecommendedWatcher,
+    widgets_path: &Path,
+    watched: &mut HashSet<String>,
+    open: HashSet<String>,
+) {
+    for widget in watched.difference(&open) {
+        let path = widgets_path.join(widget);
+        watcher.unwatch(&path.join("ui")).ok();
+        watcher.unwatch(&path).ok();
+        debug!(target: "watch", widget = %widget, "Stopped watching widget");
+    }
+    for widget in open.difference(watched) {
+        let path = widgets_path.join(widget);
+        if let Err(e) = watcher.watch(&path, RecursiveMode::NonRecursive) {
+            warn!(target: "watch", widget = %widget, "Cannot watch widget: {}", e);
+        }
+        // widgets loaded from a dev server on test mode may not have it
+        if path.join("ui").is_dir() {
+            if let Err(e) = watcher.watch(&path.join("ui"), RecursiveMode::Recursive) {
+                warn!(target: "watch", widget = %widget, "Cannot watch widget ui: {}", e);
+            }
+        }
+        debug!(target: "watch", widget = %widget, "Watching widget");
+    }
+    *watched = open;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn changes_are_classified_by_widget() {
+        let root = PathBuf::from("/home/user/.config/sbbw/widgets");
+        assert_eq!(
+            classify(&root, &root.join("bar/config.toml")),
+            Some(("bar".to_string(), Change::Config))
+        );
+        assert_eq!(
+            classify(&root, &root.join("bar/ui/css/main.css")),
+            Some(("bar".to_string(), Change::Ui))
+        );
+        assert_eq!(classify(&root, &root.join("bar/scripts/run.sh")), None);
+        assert_eq!(classify(&root, &root.join("bar")), None);
+        assert_eq!(classify(&root, Path::new("/tmp/config.toml")), None);
+    }
+}
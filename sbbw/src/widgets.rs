@@ -0,0 +1,664 @@
+use crate::{
+    daemon::{write_message, DaemonResponse, ExitInfo, WidgetStatus},
+    logs::{self, log_path, LogFile},
+};
+use sbbw_widget_conf::{
+    get_widgets, get_widgets_path, instance_key, split_instance, validate_config_toml,
+    MonitorSelector, RestartPolicy, WidgetCommand, WidgetConfig, LIST_MONITORS_ARG,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{error, info, warn};
+
+/// How often the daemon checks if its widgets are still alive
+pub const REAP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A widget running longer than this is considered healthy and its retries are reset
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+///
+/// Bookkeeping of a widget known by the daemon
+///
+/// The state is kept after the process exits, so `sbbw status` can show
+/// how and when the widget died
+///
+#[derive(Default)]
+pub struct WidgetState {
+    pub child: Option<Child>,
+    /// Arguments passed to `sbbw-widget`, reused on restarts
+    pub args: Vec<String>,
+    pub is_testing: bool,
+    pub started_at: Option<SystemTime>,
+    pub restarts: u32,
+    /// Consecutive restarts without reaching `STABLE_UPTIME`
+    pub retries: u32,
+    pub next_restart: Option<Instant>,
+    pub last_exit: Option<ExitInfo>,
+}
+
+impl WidgetState {
+    pub fn is_running(&self) -> bool {
+        self.child.is_some()
+    }
+
+    /// Current config of the widget, read again on each call since it may have changed
+    fn config(&self, name: &str) -> WidgetConfig {
+        widget_config(self.args.first().map(String::as_str).unwrap_or(name))
+    }
+
+    /// Check if the process exited, and record its exit status if so
+    fn poll(&mut self) -> Option<ExitInfo> {
+        let status = self.child.as_mut()?.try_wait().ok()??;
+        let exit = ExitInfo::from(status);
+        self.child = None;
+        self.last_exit = Some(exit.clone());
+        Some(exit)
+    }
+
+    /// Honour the restart policy of the widget after its process exited
+    fn schedule_restart(&mut self, name: &str, exit: &ExitInfo) {
+        let restart = self.config(name).restart;
+
+        let was_stable = self
+            .started_at
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|uptime| uptime >= STABLE_UPTIME);
+        if was_stable {
+            self.retries = 0;
+        }
+
+        if restart.should_restart(exit.code == Some(0), self.retries) {
+            let backoff = restart.backoff_for(self.retries);
+            info!(
+                target: "daemon",
+                widget = %name,
+                "Restarting widget in {:?} (attempt {}/{})",
+                backoff,
+                self.retries + 1,
+                restart.max_retries
+            );
+            self.next_restart = Some(Instant::now() + backoff);
+        } else if restart.policy != RestartPolicy::Never && self.retries >= restart.max_retries {
+            error!(
+                target: "daemon",
+                widget = %name,
+                "Widget failed {} times in a row, not restarting it",
+                self.retries
+            );
+        }
+    }
+
+    /// Try to launch the widget again later when its restart failed, while retries remain
+    fn schedule_retry(&mut self, name: &str) {
+        let restart = self.config(name).restart;
+        if self.retries < restart.max_retries {
+            self.next_restart = Some(Instant::now() + restart.backoff_for(self.retries));
+        } else {
+            error!(
+                target: "daemon",
+                widget = %name,
+                "Widget failed {} times in a row, not restarting it",
+                self.retries
+            );
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitInfo {
+    fn from(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+        ExitInfo {
+            code: status.code(),
+            signal,
+        }
+    }
+}
+
+/// All widget processes spawned by the daemon, with widget-name as key
+#[derive(Default)]
+pub struct Widgets {
+    states: HashMap<String, WidgetState>,
+}
+
+impl Widgets {
+    fn is_running(&mut self, widget: &str) -> bool {
+        !self.running(widget).is_empty()
+    }
+
+    ///
+    /// Keys of the running processes of a widget, itself or its instances on each monitor.
+    /// An instance key like `bar@DP-1` only matches that instance
+    ///
+    fn running(&mut self, widget: &str) -> Vec<String> {
+        self.reap();
+        let is_instance = split_instance(widget).1.is_some();
+        self.states
+            .iter()
+            .filter(|(key, state)| {
+                let matches = if is_instance {
+                    key.as_str() == widget
+                } else {
+                    split_instance(key).0 == widget
+                };
+                matches && state.is_running()
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    ///
+    /// Collect the widget processes that exited since the last call,
+    /// either crashed or closed from their own window, and log how they ended
+    ///
+    pub fn reap(&mut self) -> Vec<(String, ExitInfo)> {
+        let mut exited = Vec::new();
+        for (name, state) in self.states.iter_mut() {
+            if let Some(exit) = state.poll() {
+                if exit.code == Some(0) {
+                    info!(target: "daemon", widget = %name, "Widget exited ({})", exit);
+                } else {
+                    warn!(target: "daemon", widget = %name, "Widget exited ({})", exit);
+                }
+                state.schedule_restart(name, &exit);
+                exited.push((name.clone(), exit));
+            }
+        }
+        exited
+    }
+
+    ///
+    /// Reap exited widgets and launch again those whose restart backoff is over
+    ///
+    pub fn supervise(&mut self) {
+        self.supervise_with(spawn_widget);
+    }
+
+    /// `supervise` launching the widgets with `spawn`, so tests can make it fail
+    fn supervise_with(&mut self, spawn: impl Fn(&str, &[&str], bool) -> io::Result<Child>) {
+        self.reap();
+        let now = Instant::now();
+        for (name, state) in self.states.iter_mut() {
+            if state.is_running() || state.next_restart.is_none_or(|at| at > now) {
+                continue;
+            }
+            state.next_restart = None;
+            state.retries += 1;
+            state.restarts += 1;
+            let args: Vec<&str> = state.args.iter().map(String::as_str).collect();
+            match spawn(name, &args, state.is_testing) {
+                Ok(child) => {
+                    state.child = Some(child);
+                    state.started_at = Some(SystemTime::now());
+                }
+                Err(e) => {
+                    error!(target: "daemon", widget = %name, "Widget failed to restart: {}", e);
+                    state.schedule_retry(name);
+                }
+            }
+        }
+    }
+
+    pub fn open(&mut self, widget: String) -> DaemonResponse {
+        let (name, monitor) = split_instance(&widget);
+        if !is_installed(name) {
+            return DaemonResponse::NotFound;
+        }
+        // an instance key, toggled on again, opens only that instance
+        if monitor.is_none() && widget_config(&widget).monitor == MonitorSelector::All {
+            return self.open_on_all_monitors(&widget);
+        }
+        if self.is_running(&widget) {
+            warn!(target: "daemon", widget = %widget, "Widget already opened");
+            return DaemonResponse::AlreadyOpen;
+        }
+        info!(target: "daemon", widget = %widget, "Opening widget");
+        self.spawn(widget.clone(), &[&widget], false)
+    }
+
+    ///
+    /// Spawn an instance of the widget on each connected monitor without one,
+    /// keyed like `bar@DP-1`
+    ///
+    fn open_on_all_monitors(&mut self, widget: &str) -> DaemonResponse {
+        self.reap();
+        let monitors = match list_monitors() {
+            Ok(monitors) => monitors,
+            Err(e) => return DaemonResponse::SpawnError(e.to_string()),
+        };
+        let mut response = DaemonResponse::AlreadyOpen;
+        for monitor in monitors {
+            let key = instance_key(widget, &monitor);
+            if self.states.get(&key).is_some_and(WidgetState::is_running) {
+                continue;
+            }
+            info!(target: "daemon", widget = %key, "Opening widget instance");
+            match self.spawn(key.clone(), &[&key], false) {
+                DaemonResponse::Ok if response == DaemonResponse::AlreadyOpen => {
+                    response = DaemonResponse::Ok
+                }
+                DaemonResponse::Ok => {}
+                error => response = error,
+            }
+        }
+        response
+    }
+
+    ///
+    /// Ask the running processes of a widget to exit. They are waited through the returned
+    /// `Closing`, so the caller can release the lock in the meantime
+    ///
+    pub fn close(&mut self, widget: &str) -> Result<Closing, DaemonResponse> {
+        let running = self.running(widget);
+        if running.is_empty() {
+            warn!(target: "daemon", widget = %widget, "Widget not running");
+            return Err(DaemonResponse::NotRunning);
+        }
+        let mut closing = Closing::default();
+        for key in running {
+            info!(target: "daemon", widget = %key, "Closing widget");
+            let state = self.states.get_mut(&key).unwrap();
+            state.next_restart = None;
+            let timeout = Duration::from_millis(state.config(&key).shutdown_timeout);
+            let mut child = state.child.take().unwrap();
+            ask_to_exit(&mut child);
+            closing
+                .children
+                .push((key, child, Instant::now() + timeout));
+        }
+        Ok(closing)
+    }
+
+    /// Record how the processes waited by `Closing::wait` ended
+    pub fn closed(&mut self, closed: Vec<(String, io::Result<ExitStatus>)>) -> DaemonResponse {
+        let mut response = DaemonResponse::Ok;
+        for (key, result) in closed {
+            match result {
+                Ok(status) => {
+                    if let Some(state) = self.states.get_mut(&key) {
+                        state.last_exit = Some(ExitInfo::from(status));
+                    }
+                }
+                Err(e) => response = DaemonResponse::Error(e.to_string()),
+            }
+        }
+        response
+    }
+
+    pub fn test(&mut self, widget: String, url: String) -> DaemonResponse {
+        if !is_installed(&widget) {
+            return DaemonResponse::NotFound;
+        }
+        if self.is_running(&widget) {
+            warn!(target: "daemon", widget = %widget, "Widget already opened");
+            return DaemonResponse::AlreadyOpen;
+        }
+        info!(target: "daemon", widget = %widget, %url, "Opening widget on test mode");
+        self.spawn(widget.clone(), &[&widget, &url], true)
+    }
+
+    ///
+    /// Status of every installed widget, and of those spawned by the daemon
+    /// that are not installed anymore
+    ///
+    pub fn status(&mut self) -> Vec<WidgetStatus> {
+        let mut names = get_widgets().unwrap_or_default();
+        // widgets opened on each monitor are listed by their instances
+        names.retain(|name| {
+            self.states.contains_key(name)
+                || !self.states.keys().any(|key| split_instance(key).0 == name)
+        });
+        for name in self.states.keys() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names.sort();
+
+        self.reap();
+        let now = SystemTime::now();
+        names
+            .into_iter()
+            .map(|name| {
+                let mut status = WidgetStatus {
+                    name: name.clone(),
+                    ..Default::default()
+                };
+                if let Some(state) = self.states.get(&name) {
+                    status.running = state.is_running();
+                    status.pid = state.child.as_ref().map(|child| child.id());
+                    status.started_at = state
+                        .started_at
+                        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs());
+                    if status.running {
+                        status.uptime = state
+                            .started_at
+                            .and_then(|t| now.duration_since(t).ok())
+                            .map(|d| d.as_secs());
+                    }
+                    status.restarts = state.restarts;
+                    status.last_exit = state.last_exit.clone();
+                }
+                status
+            })
+            .collect()
+    }
+
+    /// Names of the widgets with a running process, once for all their instances
+    pub fn open_widgets(&mut self) -> HashSet<String> {
+        self.reap();
+        self.states
+            .iter()
+            .filter(|(_, state)| state.is_running())
+            .map(|(key, _)| split_instance(key).0.to_string())
+            .collect()
+    }
+
+    /// Ask the running processes of a widget to load their page again
+    pub fn reload_ui(&mut self, widget: &str) {
+        for key in self.running(widget) {
+            info!(target: "daemon", widget = %key, "UI changed, reloading widget");
+            let stdin = self
+                .states
+                .get_mut(&key)
+                .and_then(|s| s.child.as_mut()?.stdin.as_mut());
+            if let Some(stdin) = stdin {
+                if let Err(e) = write_message(stdin, &WidgetCommand::Reload) {
+                    warn!(target: "daemon", widget = %key, "Cannot reload widget: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Last lines of the log of a widget, known by the daemon or installed
+    pub fn logs(&self, widget: &str, lines: usize) -> DaemonResponse {
+        if !self.states.contains_key(widget) && !is_installed(widget) {
+            return DaemonResponse::NotFound;
+        }
+        match log_path(widget).and_then(|path| logs::tail(&path, lines)) {
+            Ok(lines) => DaemonResponse::Logs(lines),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => DaemonResponse::Logs(vec![]),
+            Err(e) => DaemonResponse::Error(e.to_string()),
+        }
+    }
+
+    fn spawn(&mut self, key: String, args: &[&str], is_testing: bool) -> DaemonResponse {
+        match spawn_widget(&key, args, is_testing) {
+            Ok(child) => {
+                let state = self.states.entry(key).or_default();
+                state.child = Some(child);
+                state.args = args.iter().map(|arg| arg.to_string()).collect();
+                state.is_testing = is_testing;
+                state.started_at = Some(SystemTime::now());
+                state.retries = 0;
+                state.next_restart = None;
+                DaemonResponse::Ok
+            }
+            Err(e) => DaemonResponse::SpawnError(e.to_string()),
+        }
+    }
+}
+
+///
+/// Widget processes asked to exit by `Widgets::close`. All of them were signaled at once,
+/// so waiting them takes at most the longest shutdown timeout
+///
+#[derive(Default)]
+#[must_use]
+pub struct Closing {
+    children: Vec<(String, Child, Instant)>,
+}
+
+impl Closing {
+    /// Wait the processes to exit, killing those still alive after their shutdown timeout
+    pub fn wait(self) -> Vec<(String, io::Result<ExitStatus>)> {
+        self.children
+            .into_iter()
+            .map(|(key, mut child, deadline)| {
+                let result = wait_or_kill(&key, &mut child, deadline);
+                (key, result)
+            })
+            .collect()
+    }
+}
+
+/// Close a widget without holding the lock of `widgets` while its processes exit
+pub fn close(widgets: &Mutex<Widgets>, widget: &str) -> DaemonResponse {
+    let closing = widgets.lock().unwrap().close(widget);
+    match closing {
+        Ok(closing) => {
+            let closed = closing.wait();
+            widgets.lock().unwrap().closed(closed)
+        }
+        Err(response) => response,
+    }
+}
+
+pub fn toggle(widgets: &Mutex<Widgets>, widget: String) -> DaemonResponse {
+    let mut guard = widgets.lock().unwrap();
+    if guard.is_running(&widget) {
+        drop(guard);
+        close(widgets, &widget)
+    } else {
+        guard.open(widget)
+    }
+}
+
+///
+/// Restart a widget after its config changed, so the new geometry and window flags
+/// are applied. An invalid config is reported and the widget keeps running as is
+///
+pub fn reload_config(widgets: &Mutex<Widgets>, widget: &str) {
+    let mut guard = widgets.lock().unwrap();
+    let running = guard.running(widget);
+    if running.is_empty() {
+        return;
+    }
+    let config = get_widgets_path()
+        .map_err(|e| e.to_string())
+        .and_then(|path| {
+            validate_config_toml(path.join(widget).join("config.toml")).map_err(|e| e.diagnostic())
+        });
+    if let Err(diagnostic) = config {
+        error!(
+            target: "daemon",
+            widget = %widget,
+            "Config changed but it is not valid, keeping the running widget\n{}",
+            diagnostic.trim_end()
+        );
+        return;
+    }
+
+    info!(target: "daemon", widget = %widget, "Config changed, restarting widget");
+    // widgets on test mode are opened again with their url
+    let test_url = running
+        .iter()
+        .filter_map(|key| guard.states.get(key))
+        .find(|state| state.is_testing)
+        .and_then(|state| state.args.get(1).cloned());
+    let closing = guard.close(widget);
+    drop(guard);
+    let closed = closing.map(Closing::wait).unwrap_or_default();
+
+    let mut guard = widgets.lock().unwrap();
+    guard.closed(closed);
+    let response = match test_url {
+        Some(url) => guard.test(widget.to_string(), url),
+        None => guard.open(widget.to_string()),
+    };
+    if !response.is_ok() {
+        error!(target: "daemon", widget = %widget, "Widget failed to restart: {}", response);
+    }
+}
+
+fn is_installed(widget: &str) -> bool {
+    get_widgets().is_ok_and(|widgets| widgets.iter().any(|w| w == widget))
+}
+
+/// Config of a widget or of the widget of an instance key
+fn widget_config(key: &str) -> WidgetConfig {
+    let widget = split_instance(key).0;
+    get_widgets_path()
+        .ok()
+        .and_then(|path| validate_config_toml(path.join(widget).join("config.toml")).ok())
+        .unwrap_or_default()
+}
+
+/// Ids of the connected monitors, as `sbbw-widget` names them on the instance keys
+fn list_monitors() -> io::Result<Vec<String>> {
+    let output = Command::new("sbbw-widget")
+        .arg(LIST_MONITORS_ARG)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Cannot list the monitors ({})",
+            ExitInfo::from(output.status)
+        )));
+    }
+    let monitors: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+    if monitors.is_empty() {
+        return Err(io::Error::other("No monitors found"));
+    }
+    Ok(monitors)
+}
+
+/// Ask the widget to exit through its stdin, or with SIGTERM if the pipe is closed
+fn ask_to_exit(child: &mut Child) {
+    let asked = child
+        .stdin
+        .as_mut()
+        .is_some_and(|stdin| write_message(stdin, &WidgetCommand::Shutdown).is_ok());
+    #[cfg(unix)]
+    if !asked {
+        unsafe {
+            libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = asked;
+}
+
+/// Wait the widget to exit, and kill it if it is still alive after `deadline`
+fn wait_or_kill(name: &str, child: &mut Child, deadline: Instant) -> io::Result<ExitStatus> {
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    warn!(
+        target: "daemon",
+        widget = %name,
+        "Widget did not exit after its shutdown timeout, killing it"
+    );
+    child.kill()?;
+    child.wait()
+}
+
+///
+/// Launch a `sbbw-widget` process with its stdout and stderr captured
+/// into the log file of `key`
+///
+fn spawn_widget(key: &str, args: &[&str], is_testing: bool) -> io::Result<Child> {
+    let mut log = LogFile::open(log_path(key)?)?;
+    let mode = if is_testing { " on test mode" } else { "" };
+    log.write_line("sbbw", &format!("Launching widget{} {:?}", mode, args))?;
+    let mut child = Command::new("sbbw-widget")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let log = Arc::new(Mutex::new(log));
+    if let Some(stdout) = child.stdout.take() {
+        logs::capture(stdout, "stdout", log.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        logs::capture(stderr, "stderr", log);
+    }
+    Ok(child)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use sbbw_widget_conf::CONFIG_DIR_ENV;
+    use std::fs;
+
+    fn exiting_child(code: i32) -> io::Result<Child> {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("exit {}", code))
+            .stdin(Stdio::null())
+            .spawn()
+    }
+
+    #[test]
+    fn failed_restarts_are_retried() {
+        let root = std::env::temp_dir().join(format!("sbbw-test-supervise-{}", std::process::id()));
+        let widget_dir = root.join("widgets").join("bar");
+        fs::create_dir_all(&widget_dir).unwrap();
+        fs::write(
+            widget_dir.join("config.toml"),
+            "name = \"bar\"\n[restart]\npolicy = \"on-failure\"\nmax_retries = 2\n",
+        )
+        .unwrap();
+        std::env::set_var(CONFIG_DIR_ENV, &root);
+
+        let mut widgets = Widgets::default();
+        let mut child = exiting_child(1).unwrap();
+        child.wait().unwrap();
+        let state = widgets.states.entry("bar".to_string()).or_default();
+        state.child = Some(child);
+        state.args = vec!["bar".to_string()];
+        state.started_at = Some(SystemTime::now());
+
+        let failing = |_: &str, _: &[&str], _: bool| Err(io::Error::other("cannot launch"));
+        // the crash is reaped and a restart is scheduled
+        widgets.supervise_with(failing);
+        assert!(widgets.states["bar"].next_restart.is_some());
+
+        // the restart fails to launch, and is tried again later
+        widgets.states.get_mut("bar").unwrap().next_restart = Some(Instant::now());
+        widgets.supervise_with(failing);
+        let state = &widgets.states["bar"];
+        assert!(state.child.is_none());
+        assert_eq!(state.retries, 1);
+        assert!(state.next_restart.is_some());
+
+        // the next attempt launches it
+        widgets.states.get_mut("bar").unwrap().next_restart = Some(Instant::now());
+        widgets.supervise_with(|_, _, _| exiting_child(1));
+        let state = &widgets.states["bar"];
+        assert!(state.child.is_some());
+        assert_eq!(state.retries, 2);
+        assert_eq!(state.restarts, 2);
+
+        // it crashes again with no retries left
+        let state = widgets.states.get_mut("bar").unwrap();
+        state.child.as_mut().unwrap().wait().unwrap();
+        widgets.supervise_with(failing);
+        let state = &widgets.states["bar"];
+        assert!(state.child.is_none());
+        assert!(state.next_restart.is_none());
+
+        std::env::remove_var(CONFIG_DIR_ENV);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}